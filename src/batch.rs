@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+/// Writes to several keys, applied all at once by `KvStore::write`.
+///
+/// A batch is written as a single log record, so after a crash either all
+/// of its writes are there or none of them are. Later writes to a key in
+/// the same batch override earlier ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    // (key, new value or `None` to delete it), in the order they were added
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key.
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> &mut WriteBatch {
+        self.ops.push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+        self
+    }
+
+    /// Removes a key. Unlike `KvsEngine::remove`, a key that does not exist
+    /// is not an error.
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> &mut WriteBatch {
+        self.ops.push((key.as_ref().to_vec(), None));
+        self
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// The writes of the batch in the order they were added, a `None`
+    /// value deleting the key.
+    pub(crate) fn ops(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.ops.iter().map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+}
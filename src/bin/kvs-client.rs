@@ -1,10 +1,7 @@
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter};
 use std::net::{TcpStream, ToSocketAddrs};
 
-use serde::{Deserialize, Serialize};
-use serde_json::de::{Deserializer, IoRead};
-
-use rust_kv::common::{Request, GetResponse, SetResponse, RemoveResponse};
+use rust_kv::common::{read_frame, write_frame, Request, GetResponse, SetResponse, RemoveResponse, KeysResponse, ExistsResponse, DbSizeResponse, IncrResponse};
 use rust_kv::kv::KvStore;
 use bincode::Error;
 
@@ -15,11 +12,15 @@ fn main() {
     let mut client = KvsClient::connect(DEFAULT_LISTENING_ADDRESS);
     client.set("12345".to_owned(), "hadxu123".to_owned());
     client.get("12345".to_owned());
+    client.exists("12345".to_owned());
+    client.keys("123*".to_owned());
+    client.dbsize();
+    client.incr_by("counter".to_owned(), 1);
     client.remove("12345".to_owned());
 }
 
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
 }
 
@@ -28,28 +29,26 @@ impl KvsClient {
         let tcp_reader = TcpStream::connect(addr).unwrap();
         let tcp_writer = tcp_reader.try_clone().unwrap();
         KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
+            reader: BufReader::new(tcp_reader),
             writer: BufWriter::new(tcp_writer),
         }
     }
 
     pub fn get(&mut self, key: String) {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key }).unwrap();
-        self.writer.flush().unwrap();
-        let resp = GetResponse::deserialize(&mut self.reader).unwrap();
+        write_frame(&mut self.writer, &Request::Get { key: key.into_bytes() }).unwrap();
+        let resp: GetResponse = read_frame(&mut self.reader).unwrap().unwrap();
 
         match resp {
             GetResponse::Ok(value) => {
-                println!("{}", value.unwrap())
+                println!("{}", String::from_utf8_lossy(&value.unwrap()))
             }
             GetResponse::Err(msg) => Err("err").unwrap()
         }
     }
 
     pub fn set(&mut self, key: String, value: String) {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value }).unwrap();
-        self.writer.flush().unwrap();
-        let resp = SetResponse::deserialize(&mut self.reader).unwrap();
+        write_frame(&mut self.writer, &Request::Set { key: key.into_bytes(), value: value.into_bytes() }).unwrap();
+        let resp: SetResponse = read_frame(&mut self.reader).unwrap().unwrap();
 
         match resp {
             SetResponse::Ok(()) => {
@@ -60,11 +59,8 @@ impl KvsClient {
     }
 
     pub fn remove(&mut self, key: String) {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key }).unwrap();
-        self.writer.flush().unwrap();
-
-
-        let resp = RemoveResponse::deserialize(&mut self.reader).unwrap();
+        write_frame(&mut self.writer, &Request::Remove { key: key.into_bytes() }).unwrap();
+        let resp: RemoveResponse = read_frame(&mut self.reader).unwrap().unwrap();
 
         match resp {
             RemoveResponse::Ok(()) => {
@@ -73,4 +69,54 @@ impl KvsClient {
             RemoveResponse::Err(e) => Err(e).unwrap()
         }
     }
-}
\ No newline at end of file
+
+    pub fn keys(&mut self, pattern: String) {
+        write_frame(&mut self.writer, &Request::Keys { pattern: pattern.into_bytes() }).unwrap();
+        let resp: KeysResponse = read_frame(&mut self.reader).unwrap().unwrap();
+
+        match resp {
+            KeysResponse::Ok(keys) => {
+                for key in keys {
+                    println!("{}", String::from_utf8_lossy(&key))
+                }
+            }
+            KeysResponse::Err(e) => Err(e).unwrap()
+        }
+    }
+
+    pub fn exists(&mut self, key: String) {
+        write_frame(&mut self.writer, &Request::Exists { key: key.into_bytes() }).unwrap();
+        let resp: ExistsResponse = read_frame(&mut self.reader).unwrap().unwrap();
+
+        match resp {
+            ExistsResponse::Ok(exists) => {
+                println!("{}", exists)
+            }
+            ExistsResponse::Err(e) => Err(e).unwrap()
+        }
+    }
+
+    pub fn dbsize(&mut self) {
+        write_frame(&mut self.writer, &Request::DbSize).unwrap();
+        let resp: DbSizeResponse = read_frame(&mut self.reader).unwrap().unwrap();
+
+        match resp {
+            DbSizeResponse::Ok(count) => {
+                println!("{}", count)
+            }
+            DbSizeResponse::Err(e) => Err(e).unwrap()
+        }
+    }
+
+    pub fn incr_by(&mut self, key: String, delta: i64) {
+        write_frame(&mut self.writer, &Request::IncrBy { key: key.into_bytes(), delta }).unwrap();
+        let resp: IncrResponse = read_frame(&mut self.reader).unwrap().unwrap();
+
+        match resp {
+            IncrResponse::Ok(value) => {
+                println!("{}", value)
+            }
+            IncrResponse::Err(e) => Err(e).unwrap()
+        }
+    }
+}
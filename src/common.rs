@@ -1,16 +1,49 @@
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+
+use crate::batch::WriteBatch;
+use crate::kv::ScanPage;
+
+/// Largest frame accepted from the other side of a connection.
+const MAX_FRAME_LEN: u32 = 256 * 1024 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get { key: Vec<u8> },
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+    /// Keys from `start` (inclusive) to `end` (exclusive), `None` leaving
+    /// that side open.
+    Scan { start: Option<Vec<u8>>, end: Option<Vec<u8>>, after: Option<Vec<u8>>, limit: u32 },
+    ScanPrefix { prefix: Vec<u8>, after: Option<Vec<u8>>, limit: u32 },
+    /// Keys matching a glob pattern.
+    Keys { pattern: Vec<u8> },
+    Exists { key: Vec<u8> },
+    DbSize,
+    GetVersioned { key: Vec<u8> },
+    /// Replaces the value of `key` with `new` if it is `expected`, `None`
+    /// standing for no value.
+    CompareAndSwap { key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>> },
+    SetIfAbsent { key: Vec<u8>, value: Vec<u8> },
+    SetIfVersion { key: Vec<u8>, value: Vec<u8>, version: u64 },
+    /// Adds `delta` to the integer value of `key`.
+    IncrBy { key: Vec<u8>, delta: i64 },
+    /// An operand for the merge operator to fold into the value of `key`.
+    Merge { key: Vec<u8>, operand: Vec<u8> },
+    /// Writes applied all at once.
+    Batch { batch: WriteBatch },
+    /// Starts a transaction on the connection: until `Commit` or `Rollback`,
+    /// `Get`, `Set` and `Remove` go through it.
+    Begin,
+    Commit,
+    Rollback,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
+    Ok(Option<Vec<u8>>),
     Err(String),
 }
 
@@ -26,3 +59,133 @@ pub enum RemoveResponse {
     Err(String),
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(ScanPage),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KeysResponse {
+    Ok(Vec<Vec<u8>>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExistsResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DbSizeResponse {
+    Ok(u64),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// Answers `Begin`, `Commit` and `Rollback`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransactionResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetVersionedResponse {
+    Ok(Option<(Vec<u8>, u64)>),
+    Err(String),
+}
+
+/// Answers a conditional write with whether it was applied.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ConditionalResponse {
+    Ok(bool),
+    Err(String),
+}
+
+/// Answers `IncrBy` with the new value.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IncrResponse {
+    Ok(i64),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MergeResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// Sends `message` as one frame: `[length: u32][bincode]`, so keys and
+/// values travel as raw bytes.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let payload = bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()
+}
+
+/// Receives the next frame, or `None` if the connection was closed between
+/// two frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut length = [0; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            // the peer went away halfway through a frame
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let length = u32::from_le_bytes(length);
+    if length > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", length)));
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    let message = bincode::deserialize(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some(message))
+}
+
+
+#[test]
+fn frames_carry_raw_bytes() -> io::Result<()> {
+    let mut wire = Vec::new();
+    write_frame(&mut wire, &Request::Set { key: vec![0, 159, 146, 150], value: vec![255; 3] })?;
+    write_frame(&mut wire, &Request::Remove { key: b"key".to_vec() })?;
+
+    let mut reader = &wire[..];
+    match read_frame(&mut reader)? {
+        Some(Request::Set { key, value }) => {
+            assert_eq!(key, vec![0, 159, 146, 150]);
+            assert_eq!(value, vec![255; 3]);
+        }
+        other => panic!("unexpected frame {:?}", other),
+    }
+    assert!(matches!(read_frame(&mut reader)?, Some(Request::Remove { .. })));
+    assert!(read_frame::<_, Request>(&mut reader)?.is_none());
+
+    // a frame cut short is an error, not the end of the stream
+    let mut reader = &wire[..wire.len() - 1];
+    read_frame::<_, Request>(&mut reader)?;
+    assert!(read_frame::<_, Request>(&mut reader).is_err());
+
+    // even if it is cut short within its length
+    let first_len = 4 + u32::from_le_bytes([wire[0], wire[1], wire[2], wire[3]]) as usize;
+    let mut reader = &wire[..first_len + 2];
+    read_frame::<_, Request>(&mut reader)?;
+    match read_frame::<_, Request>(&mut reader) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        other => panic!("expected an unexpected end of stream, got {:?}", other),
+    }
+    Ok(())
+}
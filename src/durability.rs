@@ -0,0 +1,195 @@
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error};
+
+/// When writes are flushed to stable storage with `fsync`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never call `fsync`, the operating system flushes whenever it likes.
+    #[default]
+    Never,
+    /// `fsync` every write before `set`/`remove` returns.
+    Always,
+    /// `fsync` from a background thread at the given interval, a crash can
+    /// lose the writes of the last interval.
+    Interval(Duration),
+    /// `fsync` before `set`/`remove` returns, but let writers that arrive
+    /// while a sync is running share the next one.
+    GroupCommit,
+}
+
+/// Applies a `SyncPolicy` to the writes of one store.
+///
+/// The writer reports every append through `appended` and gets a ticket back
+/// from `ticket`. Once the writer lock is released, `wait` blocks until the
+/// write behind that ticket is durable.
+pub(crate) struct Syncer {
+    policy: SyncPolicy,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct SyncState {
+    // the file currently appended to
+    file: Option<Arc<File>>,
+    // number of appends so far, a ticket is the value after an append
+    written: u64,
+    // every ticket up to this one is durable
+    synced: u64,
+    // a group commit leader is running `fsync`
+    syncing: bool,
+}
+
+impl Syncer {
+    pub(crate) fn new(policy: SyncPolicy) -> Arc<Syncer> {
+        let syncer = Arc::new(Syncer {
+            policy,
+            state: Mutex::new(SyncState::default()),
+            synced: Condvar::new(),
+        });
+        if let SyncPolicy::Interval(interval) = policy {
+            spawn_flusher(Arc::downgrade(&syncer), interval);
+        }
+        syncer
+    }
+
+    /// Records an append to `file`, the writer lock must be held.
+    pub(crate) fn appended(&self, file: &Arc<File>) -> io::Result<()> {
+        if self.policy == SyncPolicy::Never {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        match state.file {
+            Some(ref current) if Arc::ptr_eq(current, file) => {}
+            _ => state.file = Some(file.clone()),
+        }
+        if self.policy == SyncPolicy::Always {
+            file.sync_data()?;
+            state.synced = state.written;
+        }
+        Ok(())
+    }
+
+    /// Flushes the current file before the writer moves on to another one,
+    /// the writer lock must be held.
+    pub(crate) fn rotate(&self) -> io::Result<()> {
+        if self.policy == SyncPolicy::Never {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(file) = state.file.take() {
+            file.sync_data()?;
+        }
+        state.synced = state.written;
+        self.synced.notify_all();
+        Ok(())
+    }
+
+    /// The ticket of the latest append, the writer lock must be held.
+    pub(crate) fn ticket(&self) -> u64 {
+        self.state.lock().unwrap().written
+    }
+
+    /// Blocks until the append behind `ticket` is durable, if the policy
+    /// asks for it. Must be called without holding the writer lock.
+    pub(crate) fn wait(&self, ticket: u64) -> io::Result<()> {
+        if self.policy != SyncPolicy::GroupCommit {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        while state.synced < ticket {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // become the leader: one fsync covers every append so far
+            state.syncing = true;
+            let target = state.written;
+            let file = state.file.clone();
+            drop(state);
+
+            let result = file.map_or(Ok(()), |file| file.sync_data());
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                debug!("Group commit synced {} appends", target.saturating_sub(state.synced));
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Syncs whatever has been appended since the last sync.
+    fn sync_pending(&self) -> io::Result<()> {
+        let (target, file) = {
+            let state = self.state.lock().unwrap();
+            if state.synced >= state.written {
+                return Ok(());
+            }
+            (state.written, state.file.clone())
+        };
+        if let Some(file) = file {
+            file.sync_data()?;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.synced = state.synced.max(target);
+        Ok(())
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync_pending() {
+            error!("Failed to sync the log on close: {}", e);
+        }
+    }
+}
+
+/// Syncs in the background until the `Syncer` is dropped.
+fn spawn_flusher(syncer: Weak<Syncer>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match syncer.upgrade() {
+            Some(syncer) => {
+                if let Err(e) = syncer.sync_pending() {
+                    error!("Background sync failed: {}", e);
+                }
+            }
+            None => break,
+        }
+    });
+}
+
+
+#[test]
+fn tickets_become_durable() -> io::Result<()> {
+    let temp_dir = tempfile::TempDir::new().expect("unable to create temporary working directory");
+    let file = Arc::new(File::create(temp_dir.path().join("log"))?);
+
+    for policy in &[SyncPolicy::Always, SyncPolicy::GroupCommit, SyncPolicy::Interval(Duration::from_millis(1))] {
+        let syncer = Syncer::new(*policy);
+        syncer.appended(&file)?;
+        syncer.appended(&file)?;
+        let ticket = syncer.ticket();
+        assert_eq!(ticket, 2);
+        syncer.wait(ticket)?;
+        if *policy == SyncPolicy::Interval(Duration::from_millis(1)) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(syncer.state.lock().unwrap().synced, 2, "{:?}", policy);
+    }
+
+    let syncer = Syncer::new(SyncPolicy::Never);
+    syncer.appended(&file)?;
+    assert_eq!(syncer.ticket(), 0);
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::batch::WriteBatch;
+use crate::kv::{prefix_end, ScanPage};
+use crate::txn::Transaction;
+pub use crate::kv::{KvsError, Result};
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Trait for a key value storage engine.
+///
+/// `KvStore` is the log-structured implementation shipped with this crate,
+/// other backends (or mocks in tests) can be plugged into `KvsServer` by
+/// implementing this trait. Engines are cloned once per connection, so
+/// clones must share their underlying storage.
+///
+/// Only `set_bytes`, `get_bytes` and `remove_bytes` have to be implemented.
+/// The other operations return `KvsError::Unsupported` unless overridden.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a key to arbitrary bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value of a given key into `buf`, replacing its contents, so
+    /// a buffer can be reused across lookups.
+    ///
+    /// Returns `false`, leaving `buf` alone, if the given key does not exist.
+    fn get_bytes_into<K: AsRef<[u8]>>(&self, key: K, buf: &mut Vec<u8>) -> Result<bool> {
+        match self.get_bytes(key)? {
+            Some(value) => {
+                buf.clear();
+                buf.extend_from_slice(&value);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not found.
+    fn remove_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<()>;
+
+    /// Gets the value of a given key along with its version.
+    ///
+    /// Every write gives its key a new, higher version. Keys last written
+    /// before versions existed have version 0.
+    fn get_versioned<K: AsRef<[u8]>>(&self, _key: K) -> Result<Option<(Vec<u8>, u64)>> {
+        Err(KvsError::Unsupported("get_versioned"))
+    }
+
+    /// Atomically adds `delta` to the value of a key, a decimal integer, and
+    /// returns the result. A missing key counts as 0.
+    ///
+    /// Returns `KvsError::NotAnInteger` if the value is not an integer, or
+    /// the result would not fit in an `i64`.
+    fn incr_by<K: AsRef<[u8]>>(&self, _key: K, _delta: i64) -> Result<i64> {
+        Err(KvsError::Unsupported("incr_by"))
+    }
+
+    /// Atomically subtracts `delta` from the value of a key, see `incr_by`.
+    fn decr_by<K: AsRef<[u8]>>(&self, key: K, delta: i64) -> Result<i64> {
+        match delta.checked_neg() {
+            Some(delta) => self.incr_by(key, delta),
+            None => Err(KvsError::NotAnInteger(String::from_utf8_lossy(key.as_ref()).into_owned())),
+        }
+    }
+
+    /// Writes an operand for the engine's merge operator to fold into the
+    /// value of a key.
+    ///
+    /// Returns `KvsError::NoMergeOperator` if the engine has none.
+    fn merge_bytes<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, _key: K, _operand: V) -> Result<()> {
+        Err(KvsError::Unsupported("merge_bytes"))
+    }
+
+    /// Atomically replaces the value of a key with `new` if it currently is
+    /// `expected`, `None` standing for the key not existing on either side.
+    ///
+    /// Returns whether the value was replaced.
+    fn compare_and_swap<K, E, N>(&self, _key: K, _expected: Option<E>, _new: Option<N>) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+        E: AsRef<[u8]>,
+        N: AsRef<[u8]>,
+    {
+        Err(KvsError::Unsupported("compare_and_swap"))
+    }
+
+    /// Sets the value of a key only if it does not exist yet.
+    ///
+    /// Returns whether the value was set.
+    fn set_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<bool> {
+        self.compare_and_swap(key, None::<&[u8]>, Some(value))
+    }
+
+    /// Sets the value of a key only if its current version is `version`, as
+    /// returned by `get_versioned`.
+    ///
+    /// Returns whether the value was set.
+    fn set_if_version<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, _key: K, _value: V, _version: u64) -> Result<bool> {
+        Err(KvsError::Unsupported("set_if_version"))
+    }
+
+    /// Applies all writes of `batch` atomically.
+    fn write_batch(&self, _batch: &WriteBatch) -> Result<()> {
+        Err(KvsError::Unsupported("write_batch"))
+    }
+
+    /// Returns the highest version written so far.
+    fn last_version(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("last_version"))
+    }
+
+    /// Starts a transaction, see `Transaction`.
+    fn begin(&self) -> Result<Transaction<Self>> {
+        Ok(Transaction::new(self.clone(), self.last_version()?))
+    }
+
+    /// Commits the writes of a transaction that began at version `start`
+    /// and read `reads`, each key with its version or `None` if missing.
+    ///
+    /// Returns `KvsError::Conflict` if a key read has changed, or a key
+    /// written has a version above `start`.
+    fn commit_transaction(&self, _reads: &BTreeMap<Vec<u8>, Option<u64>>, _start: u64, _batch: &WriteBatch) -> Result<()> {
+        Err(KvsError::Unsupported("commit_transaction"))
+    }
+
+    /// Returns whether a given key exists, without reading its value.
+    fn contains_key_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        Ok(self.get_bytes(key)?.is_some())
+    }
+
+    /// Returns, in key order, the keys matching a glob `pattern`.
+    fn keys_matching(&self, _pattern: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Err(KvsError::Unsupported("keys_matching"))
+    }
+
+    /// Returns the number of keys.
+    fn key_count(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("key_count"))
+    }
+
+    /// Returns, in key order, up to `limit` pairs with keys between `start`
+    /// and `end`, skipping keys up to and including `after`.
+    ///
+    /// A page with a `resume` key is continued by passing it as `after`.
+    fn scan_bytes(&self, _start: Bound<&[u8]>, _end: Bound<&[u8]>, _after: Option<&[u8]>, _limit: usize) -> Result<ScanPage> {
+        Err(KvsError::Unsupported("scan_bytes"))
+    }
+
+    /// Returns, in key order, up to `limit` pairs with keys starting with
+    /// `prefix`, skipping keys up to and including `after`.
+    fn scan_prefix_bytes(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Result<ScanPage> {
+        let end = prefix_end(prefix);
+        self.scan_bytes(Bound::Included(prefix), end.as_ref().map(Vec::as_slice), after, limit)
+    }
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set<K: AsRef<str>, V: AsRef<str>>(&self, key: K, value: V) -> Result<()> {
+        self.set_bytes(key.as_ref(), value.as_ref())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist, and
+    /// `KvsError::InvalidUtf8` if its value is not a string.
+    fn get<K: AsRef<str>>(&self, key: K) -> Result<Option<String>> {
+        match self.get_bytes(key.as_ref())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not found.
+    fn remove<K: AsRef<str>>(&self, key: K) -> Result<()> {
+        self.remove_bytes(key.as_ref())
+    }
+}
+
+
+/// An engine with nothing but the required operations.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct MapEngine(Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>);
+
+#[cfg(test)]
+impl KvsEngine for MapEngine {
+    fn set_bytes<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        self.0.lock().unwrap().insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn get_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.lock().unwrap().get(key.as_ref()).cloned())
+    }
+
+    fn remove_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        match self.0.lock().unwrap().remove(key.as_ref()) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::NonExistentKey(String::from_utf8_lossy(key.as_ref()).into_owned())),
+        }
+    }
+}
+
+#[test]
+fn minimal_engine() -> Result<()> {
+    let engine = MapEngine::default();
+    engine.set("key", "value")?;
+    assert_eq!(engine.get("key")?, Some("value".to_owned()));
+    assert!(engine.contains_key_bytes("key")?);
+    assert!(matches!(engine.incr_by("key", 1), Err(KvsError::Unsupported("incr_by"))));
+    assert!(matches!(engine.begin(), Err(KvsError::Unsupported("last_version"))));
+    assert!(matches!(engine.key_count(), Err(KvsError::Unsupported("key_count"))));
+    engine.remove("key")?;
+    assert!(!engine.contains_key_bytes("key")?);
+    Ok(())
+}
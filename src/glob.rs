@@ -0,0 +1,131 @@
+/// Matches `key` against a glob `pattern`, byte by byte.
+///
+/// `*` matches any run of bytes, `?` any single byte, `[abc]`, `[a-z]` and
+/// `[^abc]` a byte of (or not of) a class, and `\` escapes the next byte.
+pub(crate) fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // where to retry if the bytes after the last `*` stop matching
+    let mut backtrack = None;
+    while k < key.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, key[k]),
+            Some(b'\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == key[k] { Some(p + 2) } else { None }
+            }
+            Some(byte) if *byte == key[k] => Some(p + 1),
+            _ => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                k += 1;
+            }
+            // let the last `*` swallow one more byte
+            (None, Some((star, start))) => {
+                backtrack = Some((star, start + 1));
+                p = star + 1;
+                k = start + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|byte| *byte == b'*')
+}
+
+/// Matches `byte` against the class starting at `pattern[open]`, returns
+/// where the pattern continues if it matched.
+///
+/// A class that is never closed matches a literal `[`.
+fn match_class(pattern: &[u8], open: usize, byte: u8) -> Option<usize> {
+    let mut i = open + 1;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    loop {
+        let mut low = match pattern.get(i) {
+            None => return if byte == b'[' { Some(open + 1) } else { None },
+            Some(b']') => break,
+            Some(b'\\') if i + 1 < pattern.len() => {
+                i += 1;
+                pattern[i]
+            }
+            Some(low) => *low,
+        };
+        let mut high = low;
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|high| *high != b']') {
+            high = pattern[i + 2];
+            i += 2;
+        }
+        if low > high {
+            std::mem::swap(&mut low, &mut high);
+        }
+        matched |= low <= byte && byte <= high;
+        i += 1;
+    }
+    if matched != negated { Some(i + 1) } else { None }
+}
+
+/// The bytes every key matching `pattern` starts with.
+pub(crate) fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut i = 0;
+    while let Some(byte) = pattern.get(i) {
+        match byte {
+            b'*' | b'?' | b'[' => break,
+            b'\\' if i + 1 < pattern.len() => {
+                prefix.push(pattern[i + 1]);
+                i += 2;
+            }
+            _ => {
+                prefix.push(*byte);
+                i += 1;
+            }
+        }
+    }
+    prefix
+}
+
+
+#[test]
+fn glob_patterns() {
+    let cases: &[(&str, &str, bool)] = &[
+        ("*", "", true),
+        ("*", "anything", true),
+        ("user:*", "user:42", true),
+        ("user:*", "admin", false),
+        ("*:42", "user:42", true),
+        ("u*r*2", "user:42", true),
+        ("u*r*3", "user:42", false),
+        ("h?llo", "hello", true),
+        ("h?llo", "hllo", false),
+        ("h[ae]llo", "hallo", true),
+        ("h[ae]llo", "hillo", false),
+        ("h[^e]llo", "hallo", true),
+        ("h[^e]llo", "hello", false),
+        ("h[a-c]llo", "hbllo", true),
+        ("h[c-a]llo", "hbllo", true),
+        ("h[a-c]llo", "hdllo", false),
+        ("h\\*llo", "h*llo", true),
+        ("h\\*llo", "hello", false),
+        ("h[llo", "h[llo", true),
+        ("a*", "b", false),
+        ("", "", true),
+        ("", "a", false),
+    ];
+    for (pattern, key, expected) in cases {
+        assert_eq!(glob_match(pattern.as_bytes(), key.as_bytes()), *expected, "{:?} on {:?}", pattern, key);
+    }
+    assert!(glob_match(b"\xff*", &[0xff, 0]));
+
+    assert_eq!(literal_prefix(b"user:*"), b"user:");
+    assert_eq!(literal_prefix(b"a\\*b?c"), b"a*b");
+    assert_eq!(literal_prefix(b"[ab]"), b"");
+}
@@ -0,0 +1,152 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::kv::Result;
+use crate::layout::LogDir;
+use crate::record;
+
+/// Where a record of a sealed log file is, without its value.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Hint {
+    pub(crate) key: Vec<u8>,
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    pub(crate) tombstone: bool,
+    /// Whether the record is a merge operand.
+    pub(crate) merge: bool,
+    /// Expiry time of the value, in milliseconds since the Unix epoch.
+    pub(crate) expires_at: Option<u64>,
+    pub(crate) version: u64,
+    /// When the record was written, in milliseconds since the Unix epoch.
+    pub(crate) written_at: u64,
+}
+
+/// How much of the end of a log file its hints are checked against.
+const TAIL_LEN: u64 = 4096;
+
+/// The hints of one log file, stored next to it as a single record.
+#[derive(Serialize, Deserialize)]
+struct HintFile {
+    // length of the log file the hints were written for
+    log_len: u64,
+    // checksum of the last `TAIL_LEN` bytes of that log file
+    tail_crc: u32,
+    hints: Vec<Hint>,
+}
+
+/// Writes the hints of sealed log file `gen`, which is `log_len` bytes long.
+///
+/// The file is synced and renamed into place, so a crash never leaves a
+/// partial one.
+pub(crate) fn write_hints(dir: &LogDir, gen: u64, log_len: u64, hints: Vec<Hint>) -> Result<()> {
+    let tail_crc = tail_checksum(dir, gen, log_len)?;
+    let payload = bincode::serialize(&HintFile { log_len, tail_crc, hints })?;
+    let path = dir.hint_path(gen);
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&record::encode(&payload))?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    dir.sync()?;
+    Ok(())
+}
+
+/// Reads the hints of log file `gen`, which is `log_len` bytes long.
+///
+/// Hints only save reading the log file, so a missing, damaged or outdated
+/// hint file is reported as `None` and the log file is replayed instead. So
+/// is one whose log file changed in its last `TAIL_LEN` bytes, which is all
+/// of the log file that is checked when its hints are used.
+pub(crate) fn read_hints(dir: &LogDir, gen: u64, log_len: u64) -> Result<Option<Vec<Hint>>> {
+    let bytes = match fs::read(dir.hint_path(gen)) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint_file = record::decode(&bytes).and_then(|payload| bincode::deserialize::<HintFile>(payload).ok());
+    match hint_file {
+        Some(hint_file) if hint_file.log_len == log_len && hint_file.tail_crc == tail_checksum(dir, gen, log_len)? => {
+            Ok(Some(hint_file.hints))
+        }
+        _ => {
+            warn!("Ignoring unusable hints of {}", dir.log_name(gen));
+            Ok(None)
+        }
+    }
+}
+
+/// The checksum of the last `TAIL_LEN` bytes of log file `gen`.
+fn tail_checksum(dir: &LogDir, gen: u64, log_len: u64) -> io::Result<u32> {
+    let mut file = File::open(dir.log_path(gen))?;
+    let start = log_len.saturating_sub(TAIL_LEN);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::with_capacity((log_len - start) as usize);
+    file.take(log_len - start).read_to_end(&mut tail)?;
+    Ok(crc32fast::hash(&tail))
+}
+
+/// Removes the hints of log file `gen`, if it has any.
+pub(crate) fn remove_hints(dir: &LogDir, gen: u64) {
+    match fs::remove_file(dir.hint_path(gen)) {
+        Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+            warn!("Failed to remove the hints of {}: {}", dir.log_name(gen), e)
+        }
+        _ => {}
+    }
+}
+
+
+#[test]
+fn hints_round_trip() -> Result<()> {
+    let temp_dir = tempfile::TempDir::new().expect("unable to create temporary working directory");
+    let dir = LogDir::new(temp_dir.path().to_owned(), "log-".to_owned());
+    let hints = vec![
+        Hint {
+            key: b"key1".to_vec(),
+            offset: 0,
+            length: 40,
+            tombstone: false,
+            merge: true,
+            expires_at: Some(1_600_000_000_000),
+            version: 7,
+            written_at: 1_500_000_000_000,
+        },
+        Hint {
+            key: b"key1".to_vec(),
+            offset: 40,
+            length: 30,
+            tombstone: true,
+            merge: false,
+            expires_at: None,
+            version: 8,
+            written_at: 1_500_000_001_000,
+        },
+    ];
+    fs::write(dir.log_path(1), vec![7; 90])?;
+    assert_eq!(read_hints(&dir, 1, 70)?, None);
+
+    write_hints(&dir, 1, 70, hints.clone())?;
+    assert_eq!(read_hints(&dir, 1, 70)?, Some(hints));
+    // the log file changed since the hints were written
+    assert_eq!(read_hints(&dir, 1, 90)?, None);
+    let mut log = fs::read(dir.log_path(1))?;
+    log[69] ^= 1;
+    fs::write(dir.log_path(1), &log)?;
+    assert_eq!(read_hints(&dir, 1, 70)?, None);
+    log[69] ^= 1;
+    fs::write(dir.log_path(1), &log)?;
+
+    let mut bytes = fs::read(dir.hint_path(1))?;
+    *bytes.last_mut().unwrap() ^= 1;
+    fs::write(dir.hint_path(1), bytes)?;
+    assert_eq!(read_hints(&dir, 1, 70)?, None);
+
+    remove_hints(&dir, 1);
+    remove_hints(&dir, 1);
+    assert!(!dir.hint_path(1).exists());
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, SystemTime};
+
+/// A value a key had, as listed by `KvStore::history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revision {
+    pub version: u64,
+    /// When the value was written. Records from before write times were
+    /// kept read as written at the Unix epoch.
+    pub written_at: SystemTime,
+    /// `None` if the key was removed.
+    pub value: Option<Vec<u8>>,
+}
+
+/// A point in the past of the store, to read a key as of with
+/// `KvStore::get_at`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum At {
+    /// Right after the write with this version.
+    Version(u64),
+    /// At this time.
+    Time(SystemTime),
+}
+
+/// How much of the history of each key is kept, see
+/// `KvStoreOptions::keep_versions` and `KvStoreOptions::keep_history_for`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Retention {
+    pub(crate) versions: usize,
+    pub(crate) window: Option<Duration>,
+}
+
+impl Retention {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.versions > 0 || self.window.is_some()
+    }
+
+    /// Whether an older value that `newer` writes to its key came after is
+    /// still kept at `now`, the first of them written at `replaced_at`. Times
+    /// are in milliseconds since the Unix epoch.
+    pub(crate) fn keeps(&self, newer: usize, replaced_at: u64, now: u64) -> bool {
+        newer <= self.versions
+            || self.window.is_some_and(|window| now < replaced_at.saturating_add(window.as_millis() as u64))
+    }
+}
+
+
+#[test]
+fn retention_policies() {
+    let none = Retention::default();
+    assert!(!none.is_enabled());
+    assert!(!none.keeps(1, 1000, 1000));
+
+    let versions = Retention { versions: 2, window: None };
+    assert!(versions.keeps(1, 0, 1000));
+    assert!(versions.keeps(2, 0, 1000));
+    assert!(!versions.keeps(3, 1000, 1000));
+
+    let window = Retention { versions: 0, window: Some(Duration::from_secs(1)) };
+    assert!(window.is_enabled());
+    assert!(window.keeps(5, 500, 1000));
+    assert!(!window.keeps(5, 0, 1000));
+
+    let both = Retention { versions: 1, window: Some(Duration::from_secs(1)) };
+    assert!(both.keeps(1, 0, 5000));
+    assert!(both.keeps(3, 4500, 5000));
+    assert!(!both.keeps(2, 0, 5000));
+}
@@ -1,19 +1,122 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use std::fs::{self, File, create_dir_all, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
-use std::path::Path;
-use log::debug;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::string::FromUtf8Error;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info, warn};
 use failure::Fail;
 use tempfile::TempDir;
 use walkdir::WalkDir;
-
-#[derive(Debug)]
+use crate::batch::WriteBatch;
+use crate::durability::Syncer;
+use crate::engine::KvsEngine;
+use crate::glob::{glob_match, literal_prefix};
+use crate::hint::{read_hints, remove_hints, write_hints, Hint};
+use crate::history::{At, Retention, Revision};
+use crate::layout::LogDir;
+use crate::lock::DirLock;
+use crate::manifest::Manifest;
+#[cfg(test)]
+use crate::manifest::FORMAT_VERSION;
+use crate::merge::parse_integer;
+use crate::options::{KvStoreOptions, OpenProgress, SharedMergeOperator};
+use crate::pool::{read_exact_at, FilePool, LogFile, Pinned};
+use crate::unframed;
+use crate::record::{self, RecordScanner, Scanned};
+use crate::segment::{pick_dirtiest, SegmentInfo, SegmentStats};
+use crate::txn::Transaction;
+
+#[derive(Debug, Clone, Copy)]
 struct LogPointer {
-    file: Arc<File>,
+    gen: u64,
     offset: u64,
     length: u64,
+    // in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+    version: u64,
+    // in milliseconds since the Unix epoch
+    written_at: u64,
+    // the newest operand of a value folded from the records in `Merges`
+    merge: bool,
+}
+
+impl LogPointer {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn same_record(&self, other: &LogPointer) -> bool {
+        self.gen == other.gen && self.offset == other.offset
+    }
+}
+
+/// An older value of a key kept by the retention policy, or its removal.
+#[derive(Debug, Clone)]
+struct OldVersion {
+    lp: LogPointer,
+    removed: bool,
+    // what a merged value is folded from once the merge chain of its key
+    // no longer holds it
+    chain: Option<Arc<MergeChain>>,
+}
+
+/// Where one of the values of a key is read from.
+enum Stored {
+    Removed,
+    Record(Arc<LogFile>),
+    // the records a merged value is folded from, and whether the first of
+    // them is the value merged into
+    Folded(Vec<(LogPointer, Arc<LogFile>)>, bool),
+}
+
+/// The older values of every key that has any, oldest first.
+type History = HashMap<Vec<u8>, VecDeque<OldVersion>>;
+
+/// The records a merged value is folded from: the value merged into, if
+/// there was one, and the operands written since, oldest first.
+#[derive(Debug, Clone)]
+struct MergeChain {
+    base: Option<LogPointer>,
+    operands: Vec<LogPointer>,
+}
+
+impl MergeChain {
+    fn records(&self) -> impl Iterator<Item = &LogPointer> {
+        self.base.iter().chain(&self.operands)
+    }
+
+    /// Whether `prefix` holds the same base and the oldest operands.
+    fn starts_with(&self, prefix: &MergeChain) -> bool {
+        let same_base = match (self.base, prefix.base) {
+            (Some(base), Some(prefix_base)) => base.same_record(&prefix_base),
+            (base, prefix_base) => base.is_none() && prefix_base.is_none(),
+        };
+        same_base
+            && self.operands.len() >= prefix.operands.len()
+            && self.operands.iter().zip(&prefix.operands).all(|(a, b)| a.same_record(b))
+    }
+}
+
+/// Where the current value of every key is.
+type Index = BTreeMap<Vec<u8>, LogPointer>;
+
+/// The merge chains of every key whose value is merged.
+type Merges = HashMap<Vec<u8>, MergeChain>;
+
+/// Milliseconds since the Unix epoch, the unit expiry and write times are
+/// kept in.
+fn now_millis() -> u64 {
+    millis(SystemTime::now())
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
 }
 
 
@@ -21,25 +124,92 @@ struct LogPointer {
 enum CommandType {
     Set,
     Remove,
+    /// A set whose value expires at the given time, in milliseconds since
+    /// the Unix epoch.
+    SetExpiring(u64),
+    /// A run of records written as one, held in the value. Its key is empty.
+    Batch,
+    /// An operand for the merge operator to fold into the value.
+    Merge,
+}
+
+impl CommandType {
+    fn expires_at(&self) -> Option<u64> {
+        match *self {
+            CommandType::SetExpiring(expires_at) => Some(expires_at),
+            CommandType::Set | CommandType::Remove | CommandType::Batch | CommandType::Merge => None,
+        }
+    }
 }
 
+/// A log record, borrowing its key and value from the caller or from the
+/// buffer it is read from.
 #[derive(Serialize, Deserialize, Debug)]
-struct Command {
+struct Command<'a> {
     typ: CommandType,
-    key: String,
-    value: String,
+    key: &'a [u8],
+    value: &'a [u8],
+    // taken from a sequence shared by all keys, so it only ever grows
+    version: u64,
+    // in milliseconds since the Unix epoch
+    written_at: u64,
 }
 
+/// A log record as written before records carried a write time.
+#[derive(Deserialize)]
+struct UntimedCommand<'a> {
+    typ: CommandType,
+    key: &'a [u8],
+    value: &'a [u8],
+    version: u64,
+}
 
-#[derive(Default)]
+/// A log record as written before records carried a version.
+#[derive(Deserialize)]
+struct LegacyCommand<'a> {
+    typ: CommandType,
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> Command<'a> {
+    /// Decodes the payload of a record. Records written before versions or
+    /// write times existed end where those would start, and get 0 for them.
+    fn decode(payload: &'a [u8]) -> Result<Command<'a>> {
+        let e = match bincode::deserialize(payload) {
+            Ok(cmd) => return Ok(cmd),
+            Err(e) => e,
+        };
+        if let Ok(UntimedCommand { typ, key, value, version }) = bincode::deserialize(payload) {
+            return Ok(Command { typ, key, value, version, written_at: 0 });
+        }
+        match bincode::deserialize::<LegacyCommand>(payload) {
+            Ok(LegacyCommand { typ, key, value }) => Ok(Command { typ, key, value, version: 0, written_at: 0 }),
+            Err(_) => Err(e.into()),
+        }
+    }
+
+    /// Where the value starts in `payload`, which this was decoded from.
+    fn value_offset(&self, payload: &[u8]) -> usize {
+        self.value.as_ptr() as usize - payload.as_ptr() as usize
+    }
+}
+
+
+/// A log-structured key value store.
+///
+/// Cloning is cheap: clones share the index, the open files and the writer,
+/// so a `KvStore` can be handed to as many threads as needed.
+#[derive(Clone)]
 pub struct KvStore {
-    dpath: String,
-    // ???
-    index: Option<HashMap<String, LogPointer>>,
-    // ???
-    file: Option<Arc<File>>,
-    // ???
-    uncompacted: u64,
+    // reads the index and the log files without blocking other readers
+    reader: KvStoreReader,
+    // all mutations are serialized through a single writer
+    writer: Arc<Mutex<KvStoreWriter>>,
+    // makes writes durable according to the `SyncPolicy`
+    syncer: Arc<Syncer>,
+    // compacts the log without holding up writers
+    compactor: Arc<Compactor>,
 }
 
 #[derive(Fail, Debug)]
@@ -47,12 +217,45 @@ pub enum KvsError {
     /// Non-existent key.
     #[fail(display = "Non-existent key: {}", _0)]
     NonExistentKey(String),
+    /// A value read through the `String` API is not valid UTF-8.
+    #[fail(display = "Value is not valid UTF-8: {}", _0)]
+    InvalidUtf8(#[fail(cause)] FromUtf8Error),
     /// An IO error occurred.
     #[fail(display = "{}", _0)]
     IoError(#[fail(cause)] io::Error),
     /// A Bincode error occurred.
     #[fail(display = "Bincode error: {}", _0)]
     BincodeError(#[fail(cause)] bincode::Error),
+    /// A log record does not match its checksum.
+    #[fail(display = "Corrupted record in log file {} at offset {}", gen, offset)]
+    Corruption { gen: u64, offset: u64 },
+    /// No store exists and it should not be created.
+    #[fail(display = "No store found at {:?}", _0)]
+    StoreNotFound(PathBuf),
+    /// A store exists although a new one was asked for.
+    #[fail(display = "A store already exists at {:?}", _0)]
+    StoreExists(PathBuf),
+    /// Another process has the store open.
+    #[fail(display = "The store is locked by {}", _0)]
+    Locked(String),
+    /// The store was written by an incompatible version.
+    #[fail(display = "Unsupported on-disk format version {}", _0)]
+    IncompatibleFormat(u32),
+    /// A value is not a decimal integer, or would overflow one.
+    #[fail(display = "Value of key {} is not an integer or out of range", _0)]
+    NotAnInteger(String),
+    /// Merge operands were written or read without a merge operator.
+    #[fail(display = "No merge operator is set")]
+    NoMergeOperator,
+    /// A transaction read or wrote a key that was changed concurrently.
+    #[fail(display = "Transaction conflict on key {}", _0)]
+    Conflict(String),
+    /// The engine does not implement the operation.
+    #[fail(display = "Operation not supported by this engine: {}", _0)]
+    Unsupported(&'static str),
+    /// A write was attempted on a store opened read-only.
+    #[fail(display = "The store was opened read-only")]
+    ReadOnly,
     /// A SetLoggerError occurred.
     #[fail(display = "{}", _0)]
     SetLoggerError(#[fail(cause)] log::SetLoggerError),
@@ -64,6 +267,12 @@ impl From<io::Error> for KvsError {
     }
 }
 
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::InvalidUtf8(err)
+    }
+}
+
 impl From<bincode::Error> for KvsError {
     fn from(err: bincode::Error) -> KvsError {
         KvsError::BincodeError(err)
@@ -80,360 +289,3557 @@ impl From<log::SetLoggerError> for KvsError {
 pub type Result<T> = std::result::Result<T, KvsError>;
 
 
-fn read_value(key: &String, index: &HashMap<String, LogPointer>) -> Result<Option<String>> {
-    match index.get(key).as_mut() {
-        None => Ok(None),
-        Some(lp) => {
-            let mut file = lp.file.as_ref();
-
-            file.seek(SeekFrom::Start(lp.offset))?;
-            let cmd: Command = bincode::deserialize_from(file)?;
-            Ok(Some(cmd.value))
-        }
-    }
+/// Reads commands from the log files.
+///
+/// All clones share one bounded pool of file handles. Reads are positional,
+/// so any number of them can run on the same handle at once.
+#[derive(Clone)]
+struct KvStoreReader {
+    // shared with snapshots, and copied by the next write while one is
+    index: Arc<RwLock<Arc<Index>>>,
+    // older values kept by the retention policy, always updated before the
+    // index so a value is never in neither
+    history: Arc<RwLock<History>>,
+    // what merged values are folded from, updated before the index when an
+    // operand is added and after it when the value is replaced, and shared
+    // with snapshots like the index
+    merges: Arc<RwLock<Arc<Merges>>>,
+    merge_operator: Option<SharedMergeOperator>,
+    pool: Arc<FilePool>,
+    // the log files a snapshot reads from, kept for as long as any clone
+    _pinned: Option<Arc<Pinned>>,
 }
 
-impl KvStore {
-    pub fn open(dpath: &Path) -> Result<KvStore> {
-        let dpath_full = dpath.join(".kvs");
-        if !dpath_full.exists() {
-            create_dir_all(&dpath_full)?;
-        }
-        let dpath_str = String::from(
-            dpath_full
-                // 对路径进行规范化
-                .canonicalize()?
-                .to_str()
-                .expect("directory should exist"),
-        );
-
-        debug!("Opening KvStore, dpath: '{}'", dpath_str);
-
-        Ok(KvStore {
-            index: None,
-            file: None,
-            dpath: dpath_str,
-            uncompacted: 0,
-        })
+impl KvStoreReader {
+    fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_versioned(key)?.map(|(value, _)| value))
     }
-    pub fn set(self: &mut KvStore, key: String, val: String) -> Result<()> {
-        println!("Setting '{}' => '{}'", key, val);
 
-        self.build_index()?;
-
-        let index = self.index.as_ref().expect("index undefined");
+    /// Reads the value of `key` along with its version.
+    fn read_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        let mut buf = Vec::new();
+        Ok(self.read_entry_into(key, &mut buf)?.map(|lp| (buf, lp.version)))
+    }
 
-        let existing_val = read_value(&key, index)?;
+    /// Reads the value of `key` into `buf`, which only allocates if it is
+    /// too small for the record. Returns whether the key exists.
+    fn read_value_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<bool> {
+        Ok(self.read_entry_into(key, buf)?.is_some())
+    }
 
-        if existing_val.as_ref() == Some(&val) {
-            debug!("Doing nothing since the existing value is the same");
-            return Ok(());
+    /// Reads the value of `key` into `buf`, returns where it was read from.
+    fn read_entry_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<LogPointer>> {
+        loop {
+            // only hold the index lock long enough to grab a handle, compaction
+            // only removes a log file once every handle to it has been dropped
+            let (lp, file) = {
+                let index = self.index.read().unwrap();
+                match index.get(key) {
+                    Some(lp) if !lp.is_expired(now_millis()) => (*lp, self.pool.get(lp.gen)?),
+                    _ => return Ok(None),
+                }
+            };
+            if !lp.merge {
+                read_value_at(&file, &lp, buf)?;
+                return Ok(Some(lp));
+            }
+            if self.read_merged_into(key, &lp, buf)? {
+                return Ok(Some(lp));
+            }
+            // replaced or folded by compaction meanwhile, look again
         }
+    }
 
-        if existing_val.is_some() {
-            let old_lp = index.get(&key).expect("key should be in index");
-            debug!("Adding to compaction potential: {}", old_lp.length);
-            self.uncompacted += old_lp.length;
+    /// Folds the value of `key` whose newest operand is at `lp` into `buf`.
+    /// Returns `false` if the value is no longer made up that way.
+    fn read_merged_into(&self, key: &[u8], lp: &LogPointer, buf: &mut Vec<u8>) -> Result<bool> {
+        let folded = {
+            let merges = self.merges.read().unwrap();
+            self.folded_from(merges.get(key), lp)?
+        };
+        match folded {
+            Some(Stored::Folded(records, has_base)) => {
+                *buf = self.fold(key, &records, has_base)?;
+                Ok(true)
+            }
+            _ => Ok(false),
         }
+    }
 
-        let cmd = Command {
-            typ: CommandType::Set,
-            key: key.clone(),
-            value: val.clone(),
+    /// The records the merged value whose newest operand is at `lp` is
+    /// folded from, `None` unless `chain` holds it.
+    fn folded_from(&self, chain: Option<&MergeChain>, lp: &LogPointer) -> Result<Option<Stored>> {
+        let chain = match chain {
+            Some(chain) => chain,
+            None => return Ok(None),
         };
-
-        println!("Writing set command: {}, {}", key, val);
-        self.write_command(cmd)?;
-        // println!("{:?}", self.index);
-
-
-        Ok(())
+        let newest = match chain.operands.iter().position(|operand| operand.same_record(lp)) {
+            Some(newest) => newest,
+            None => return Ok(None),
+        };
+        let mut records = Vec::with_capacity(newest + 2);
+        for record in chain.base.iter().chain(&chain.operands[..=newest]) {
+            records.push((*record, self.pool.get(record.gen)?));
+        }
+        Ok(Some(Stored::Folded(records, chain.base.is_some())))
     }
 
-    pub fn get(self: &mut KvStore, key: String) -> Result<Option<String>> {
-        println!("Getting key '{}'", key);
-        self.build_index()?;
-        let index = self.index.as_ref().unwrap();
-        let val = read_value(&key, index)?;
-        Ok(val)
+    /// Folds the value of `key` from `records`: the value merged into if
+    /// `has_base`, then the operands.
+    fn fold(&self, key: &[u8], records: &[(LogPointer, Arc<LogFile>)], has_base: bool) -> Result<Vec<u8>> {
+        let mut values = Vec::with_capacity(records.len());
+        for (record, file) in records {
+            let mut value = Vec::new();
+            read_value_at(file, record, &mut value)?;
+            values.push(value);
+        }
+        if has_base && values.len() == 1 {
+            return Ok(values.remove(0));
+        }
+        let operator = match self.merge_operator {
+            Some(ref operator) => operator,
+            None => return Err(KvsError::NoMergeOperator),
+        };
+        let (existing, operands) = if has_base {
+            (Some(values[0].as_slice()), &values[1..])
+        } else {
+            (None, &values[..])
+        };
+        let operands: Vec<&[u8]> = operands.iter().map(Vec::as_slice).collect();
+        operator.0.merge(key, existing, &operands)
     }
 
-    pub fn remove(self: &mut KvStore, key: String) -> Result<()> {
-        self.build_index()?;
-        let index = self.index.as_mut().unwrap();
-        if !index.contains_key(&key) {
-            return Err(KvsError::NonExistentKey(key))?;
+    /// Every value of `key` that is kept, oldest first and the current one
+    /// last, with where to read it from.
+    fn versions(&self, key: &[u8]) -> Result<Vec<(LogPointer, Stored)>> {
+        // the index first: a write that gets in between moves the value read
+        // here into the history before replacing it, so it is seen twice
+        let current = {
+            let index = self.index.read().unwrap();
+            match index.get(key) {
+                Some(lp) if lp.merge => {
+                    let merges = self.merges.read().unwrap();
+                    self.folded_from(merges.get(key), lp)?.map(|stored| (*lp, stored))
+                }
+                Some(lp) => Some((*lp, Stored::Record(self.pool.get(lp.gen)?))),
+                None => None,
+            }
+        };
+        let mut versions = Vec::new();
+        {
+            let history = self.history.read().unwrap();
+            let merges = self.merges.read().unwrap();
+            for old in history.get(key).into_iter().flatten() {
+                let stored = if old.removed {
+                    Stored::Removed
+                } else if old.lp.merge {
+                    // folded from the merge chain of the key while that still
+                    // holds it, which is updated after the history
+                    let chain = old.chain.as_deref().or_else(|| merges.get(key));
+                    match self.folded_from(chain, &old.lp)? {
+                        Some(stored) => stored,
+                        None => continue,
+                    }
+                } else {
+                    Stored::Record(self.pool.get(old.lp.gen)?)
+                };
+                versions.push((old.lp, stored));
+            }
         }
+        if let Some((lp, stored)) = current {
+            if !versions.iter().any(|(old, _)| old.same_record(&lp)) {
+                versions.push((lp, stored));
+            }
+        }
+        Ok(versions)
+    }
 
-        let old_cmd = index.remove(&key).expect("key not found");
-        self.uncompacted += old_cmd.length;
-
-        let cmd = Command {
-            typ: CommandType::Remove,
-            key: key.clone(),
-            value: String::new(),
+    /// Reads one of the `versions` of `key`.
+    fn read_revision(&self, key: &[u8], lp: &LogPointer, stored: &Stored) -> Result<Revision> {
+        let value = match stored {
+            Stored::Removed => None,
+            Stored::Record(file) => {
+                let mut buf = Vec::new();
+                read_value_at(file, lp, &mut buf)?;
+                Some(buf)
+            }
+            Stored::Folded(records, has_base) => Some(self.fold(key, records, *has_base)?),
         };
+        Ok(Revision {
+            version: lp.version,
+            written_at: UNIX_EPOCH + Duration::from_millis(lp.written_at),
+            value,
+        })
+    }
+
+    /// Where the current value of `key` is, if it has one.
+    fn pointer(&self, key: &[u8]) -> Option<LogPointer> {
+        let index = self.index.read().unwrap();
+        index.get(key).filter(|lp| !lp.is_expired(now_millis())).copied()
+    }
+}
 
-        debug!("Writing remove command: {}", key);
-        self.write_command(cmd)?;
+/// Reads the value of the record at `lp` into `buf`, which only allocates
+/// if it is too small for the record.
+fn read_value_at(file: &File, lp: &LogPointer, buf: &mut Vec<u8>) -> Result<()> {
+    buf.resize(lp.length as usize, 0);
+    read_exact_at(file, buf, lp.offset)?;
+    let value = {
+        let payload = record::decode(buf).ok_or(KvsError::Corruption { gen: lp.gen, offset: lp.offset })?;
+        let cmd = Command::decode(payload)?;
+        let start = record::HEADER_LEN as usize + cmd.value_offset(payload);
+        start..start + cmd.value.len()
+    };
+    // move the value to the front of the record it was read with
+    let len = value.len();
+    buf.copy_within(value, 0);
+    buf.truncate(len);
+    Ok(())
+}
 
+/// A read-only view of the store as it was when `KvStore::snapshot` was
+/// called, unaffected by later writes.
+///
+/// The log files the snapshot reads from stay on disk, even once compaction
+/// has replaced them, until it and every scan started from it are dropped.
+/// Values that expire are hidden once they do, as in the store.
+pub struct Snapshot {
+    reader: KvStoreReader,
+}
 
-        Ok(())
+impl Snapshot {
+    /// Gets the value a key had when the snapshot was taken.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        self.reader.read_value(key.as_ref())
     }
 
-    fn build_index(self: &mut KvStore) -> Result<()> {
-        // 建立索引
-        if self.index.is_some() {
-            debug!("Index already defined");
-            return Ok(());
-        }
+    /// Gets the value a key had when the snapshot was taken, along with
+    /// its version.
+    pub(crate) fn get_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        self.reader.read_versioned(key)
+    }
 
-        self.index = Some(HashMap::new());
-        let index = self.index.as_mut().expect("index should be defined");
+    /// Iterates over the pairs with keys in `range`, in key order.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Scan {
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        Scan::new(self.reader.clone(), start, end)
+    }
 
+    /// Iterates over the pairs with keys starting with `prefix`, in key order.
+    pub fn prefix<K: AsRef<[u8]>>(&self, prefix: K) -> Scan {
+        let prefix = prefix.as_ref();
+        Scan::new(self.reader.clone(), Bound::Included(prefix.to_vec()), prefix_end(prefix))
+    }
 
-        let mut uncompacted: u64 = 0;
-        let mut i = 1;
+    /// Iterates over all pairs, in key order.
+    pub fn iter(&self) -> Scan {
+        Scan::new(self.reader.clone(), Bound::Unbounded, Bound::Unbounded)
+    }
+}
 
-        loop {
-            let fpath = Path::new(&self.dpath).join(format!("log-{}", i));
-            let file_rslt = File::open(fpath.clone());
+/// Number of keys a `Scan` takes from the index at a time.
+const SCAN_BATCH: usize = 256;
+
+/// A lazy, ordered iterator over the key/value pairs in a range of keys,
+/// created by `KvStore::range`, `KvStore::prefix` or `KvStore::iter`.
+///
+/// Keys are taken from the index in batches and each value is read from the
+/// log as the iterator gets to it, so writes made during a scan may or may
+/// not be seen. A key removed before its value is read is skipped.
+pub struct Scan {
+    reader: KvStoreReader,
+    // bounds of the keys not taken from the index yet
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    keys: VecDeque<Vec<u8>>,
+}
 
-            if file_rslt.is_err() {
-                debug!("File '{}' doesn't exist, ending loop", fpath.to_str().unwrap());
-                break;
-            }
+/// One page of a scan, see `Scan::page`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanPage {
+    /// Key/value pairs, in key order.
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Last key of the page if more keys may follow, the next page starts
+    /// after it.
+    pub resume: Option<Vec<u8>>,
+}
 
-            let file = Arc::new(file_rslt.expect("file should be opened"));
-            // println!("File '{}' exists, applying to index", fpath.to_str().unwrap());
+impl Scan {
+    fn new(reader: KvStoreReader, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Scan {
+        Scan { reader, start, end, keys: VecDeque::new() }
+    }
 
-            loop {
-                let offset = file.as_ref().seek(SeekFrom::Current(0))?;
-                let read_rslt = bincode::deserialize_from(file.as_ref());
-
-                if read_rslt.is_err() {
-                    break;
-                }
-                let cmd: Command = read_rslt.expect("read result should be OK");
-                let cur_offset = file.as_ref().seek(SeekFrom::Current(0))?;
-                let cmd_length: u64 = cur_offset - offset;
-
-                match cmd.typ {
-                    CommandType::Set => {
-                        debug!("Read set command {} => {}", cmd.key, cmd.value);
-                        if let Some(old_ptr) = index.insert(
-                            cmd.key,
-                            LogPointer {
-                                file: file.clone(),
-                                offset,
-                                length: cmd_length,
-                            },
-                        ) {
-                            debug!("Overridden command can be compacted: {}", old_ptr.length);
-                            uncompacted += old_ptr.length;
-                        }
-                    }
-                    CommandType::Remove => {
-                        if let Some(old_ptr) = index.remove(&cmd.key) {
-                            uncompacted += old_ptr.length;
-                        }
-                        uncompacted += cmd_length;
-                    }
-                }
-            }
-            i += 1;
+    /// Skips every key up to and including `key`, to resume a scan from the
+    /// `resume` key of a previous page.
+    pub fn after<K: AsRef<[u8]>>(mut self, key: K) -> Scan {
+        let key = key.as_ref();
+        let skip = match &self.start {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        };
+        if skip {
+            self.start = Bound::Excluded(key.to_vec());
         }
-
-        self.uncompacted = uncompacted;
-        Ok(())
+        self.keys.retain(|pending| pending.as_slice() > key);
+        self
     }
 
-
-    fn write_command(self: &mut KvStore, cmd: Command) -> Result<()> {
-        if self.file.is_none() {
-            let mut i = 1;
-            loop {
-                let fpath = Path::new(&self.dpath).join(format!("log-{}", i));
-                if !fpath.exists() {
-                    debug!("Creating file at {}", fpath.to_str().unwrap());
-                    let file = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .create_new(true)
-                        .open(fpath)?;
-                    self.file = Some(Arc::new(file));
-                    break;
-                }
-                i += 1;
+    /// Collects the next `limit` pairs, or fewer if the scan ends first.
+    pub fn page(mut self, limit: usize) -> Result<ScanPage> {
+        let mut pairs = Vec::new();
+        // an empty page could not tell where to resume
+        while pairs.len() < limit.max(1) {
+            match self.next() {
+                Some(pair) => pairs.push(pair?),
+                None => return Ok(ScanPage { pairs, resume: None }),
             }
         }
+        let more = !self.keys.is_empty() || self.fill();
+        let resume = if more { pairs.last().map(|(key, _)| key.clone()) } else { None };
+        Ok(ScanPage { pairs, resume })
+    }
 
-
-        let serialized = bincode::serialize(&cmd)?;
-        let file = self.file.as_ref().expect("self.file");
-        let offset = file.as_ref().seek(SeekFrom::End(0))?;
-        file.as_ref().write(&serialized)?;
-        let cur_offset = file.as_ref().seek(SeekFrom::End(0))?;
-
-
-        match cmd.typ {
-            CommandType::Remove => {}
-            CommandType::Set => {
-                debug!("Writing command to file {:?}", self.file.as_ref());
-                let file = self.file.as_ref().unwrap().clone();
-
-                let lp = LogPointer { file, offset, length: cur_offset - offset };
-
-                self.index.as_mut()
-                    .expect("self.index should be defined")
-                    .insert(cmd.key, lp);
+    /// Takes the next batch of keys from the index, returns whether there
+    /// were any left.
+    fn fill(&mut self) -> bool {
+        if is_empty_range(&self.start, &self.end) {
+            return false;
+        }
+        {
+            let index = self.reader.index.read().unwrap();
+            let range = (self.start.as_ref().map(Vec::as_slice), self.end.as_ref().map(Vec::as_slice));
+            self.keys.extend(index.range::<[u8], _>(range).take(SCAN_BATCH).map(|(key, _)| key.clone()));
+        }
+        match self.keys.back() {
+            Some(last) => {
+                self.start = Bound::Excluded(last.clone());
+                true
             }
-        };
-
-        if self.uncompacted > 1024 * 1024 {
-            self.compact()?;
+            None => false,
         }
-        Ok(())
     }
+}
 
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
 
-    fn compact(&mut self) -> Result<()> {
-        let index = self.index.as_ref().expect("self.index should be defined");
-        let new_dpath = format!("{}.new", self.dpath);
-        create_dir_all(&new_dpath)?;
-        let mut file = File::create(Path::new(&new_dpath).join("log-1"))?;
-
-        for (key, _) in self.index.as_ref().expect("self.index should be defined") {
-            let value = read_value(key, index)?.expect("key should have value");
-            let cmd = Command { typ: CommandType::Set, key: key.to_owned(), value };
-            let serialized = bincode::serialize(&cmd)?;
-            file.write(&serialized)?;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.keys.is_empty() && !self.fill() {
+                return None;
+            }
+            let key = self.keys.pop_front()?;
+            match self.reader.read_value(&key) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
         }
-
-        file.flush()?;
-        self.index = None;
-        self.file = None;
-
-        let old_dpath = format!("{}.old", self.dpath);
-        fs::rename(&self.dpath, &old_dpath)?;
-        fs::rename(&new_dpath, &self.dpath)?;
-
-        fs::remove_dir_all(&old_dpath)?;
-        self.build_index()?;
-        self.uncompacted = 0;
-        Ok(())
     }
 }
 
-
-#[test]
-fn remove_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
-    Ok(())
+// `BTreeMap::range` panics on these rather than returning nothing
+fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
 }
 
-
-#[test]
-fn remove_non_existent_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
-    Ok(())
+/// The first key after every key starting with `prefix`, if there is one.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
 }
 
 
-#[test]
-fn get_stored_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+/// Appends commands to the active log file and keeps the index up to date.
+struct KvStoreWriter {
+    // keeps other processes out for as long as any clone is alive
+    _lock: DirLock,
+    dir: Arc<LogDir>,
+    options: KvStoreOptions,
+    index: Arc<RwLock<Arc<Index>>>,
+    reader: KvStoreReader,
+    syncer: Arc<Syncer>,
+    // the published list of live log files
+    manifest: Manifest,
+    // generation of the next log file to create
+    next_gen: u64,
+    // version of the next record to write
+    next_version: u64,
+    // the log file appended to, created on the first write if there is none
+    file: Option<(u64, Arc<File>)>,
+    // hints of the active log file, written out when it is sealed
+    hints: Vec<Hint>,
+    // byte counts of every log file in the manifest
+    segments: HashMap<u64, SegmentStats>,
+    // a compaction job is running in the background
+    compacting: bool,
+}
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+impl KvStoreWriter {
+    fn set(&mut self, key: &[u8], val: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let existing_val = self.reader.read_value(key)?;
+        // the writer lock is held, so the index cannot have changed meanwhile
+        let old_lp = self.index.read().unwrap().get(key).copied();
 
+        if existing_val.as_deref() == Some(val) && old_lp.is_some_and(|lp| lp.expires_at == expires_at) {
+            debug!("Doing nothing since the existing value is the same");
+            return Ok(());
+        }
+        self.put(key, val, expires_at)
+    }
 
-    println!("{:?}", store.index);
+    /// Writes a set command even if it does not change the value, giving the
+    /// key a new version.
+    fn put(&mut self, key: &[u8], val: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let cmd = Command {
+            typ: expires_at.map_or(CommandType::Set, CommandType::SetExpiring),
+            key,
+            value: val,
+            version: self.next_version,
+            written_at: now_millis(),
+        };
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        debug!("Writing set command: {:?}, {} bytes", String::from_utf8_lossy(cmd.key), cmd.value.len());
+        self.write_command(cmd)
+    }
 
-    // Open from disk again and check persistent data
-    println!("\nDropping store");
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        if self.reader.pointer(key).is_none() {
+            return Err(KvsError::NonExistentKey(String::from_utf8_lossy(key).into_owned()));
+        }
 
-    Ok(())
-}
+        let cmd = Command {
+            typ: CommandType::Remove,
+            key,
+            value: &[],
+            version: self.next_version,
+            written_at: now_millis(),
+        };
 
-#[test]
-fn overwrite_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+        debug!("Writing remove command: {:?}", String::from_utf8_lossy(cmd.key));
+        self.write_command(cmd)
+    }
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    /// Writes an operand for the merge operator to fold into `key`.
+    fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        if self.options.merge_operator.is_none() {
+            return Err(KvsError::NoMergeOperator);
+        }
+        let cmd = Command {
+            typ: CommandType::Merge,
+            key,
+            value: operand,
+            version: self.next_version,
+            written_at: now_millis(),
+        };
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        debug!("Writing merge command: {:?}, {} bytes", String::from_utf8_lossy(cmd.key), cmd.value.len());
+        self.write_command(cmd)
+    }
 
-    Ok(())
-}
+    /// Adds `delta` to the integer value of `key`, a missing key counting
+    /// as 0, and returns the result. The value keeps its expiry time.
+    fn incr_by(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let current = match self.reader.read_value(key)? {
+            Some(value) => parse_integer(key, &value)?,
+            None => 0,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| KvsError::NotAnInteger(String::from_utf8_lossy(key).into_owned()))?;
+        let expires_at = self.reader.pointer(key).and_then(|lp| lp.expires_at);
+        self.set(key, value.to_string().as_bytes(), expires_at)?;
+        Ok(value)
+    }
 
-#[test]
-fn compaction() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    /// Rewrites the value of `key` with a new expiry time, returns the one
+    /// it had before.
+    fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> Result<Option<u64>> {
+        let value = self.reader.read_value(key)?;
+        let old_lp = self.index.read().unwrap().get(key).copied();
+        match (value, old_lp) {
+            (Some(value), Some(old_lp)) => {
+                self.set(key, &value, expires_at)?;
+                Ok(old_lp.expires_at)
+            }
+            _ => Err(KvsError::NonExistentKey(String::from_utf8_lossy(key).into_owned())),
+        }
+    }
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    /// Applies `new` to `key` if its current value is `expected`, where
+    /// `None` stands for no value. Returns whether it did.
+    fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool> {
+        let current = self.reader.read_value(key)?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        match (current, new) {
+            (_, Some(new)) => {
+                let expires_at = self.reader.pointer(key).and_then(|lp| lp.expires_at);
+                self.set(key, new, expires_at)?
+            }
+            (Some(_), None) => self.remove(key)?,
+            (None, None) => {}
+        }
+        Ok(true)
+    }
 
-    let mut current_size = dir_size();
-    for iter in 0..10 {
-        for key_id in 0..10 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
+    /// Sets `key` if its current value has `version`, returns whether it did.
+    ///
+    /// The value is written even if it is the same, so that the version
+    /// changes and a second caller holding the same version fails.
+    fn set_if_version(&mut self, key: &[u8], value: &[u8], version: u64) -> Result<bool> {
+        match self.reader.pointer(key) {
+            Some(lp) if lp.version == version => {
+                self.put(key, value, lp.expires_at)?;
+                Ok(true)
+            }
+            _ => Ok(false),
         }
+    }
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+    /// Writes `batch` unless a key in `reads` no longer has the version read,
+    /// or a key written has a version above `start`.
+    fn commit_transaction(&mut self, reads: &BTreeMap<Vec<u8>, Option<u64>>, start: u64, batch: &WriteBatch) -> Result<()> {
+        let version = |key: &[u8]| self.reader.pointer(key).map(|lp| lp.version);
+        let read_changed = reads.iter().find(|(key, read)| version(key) != **read).map(|(key, _)| key.as_slice());
+        let written_since = || batch.ops().map(|(key, _)| key).find(|key| version(key).is_some_and(|v| v > start));
+        if let Some(key) = read_changed.or_else(written_since) {
+            return Err(KvsError::Conflict(String::from_utf8_lossy(key).into_owned()));
         }
-        // Compaction triggered
+        self.write_batch(batch)
+    }
 
-        drop(store);
-        // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..10 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+    /// The log file to append to, created if there is none.
+    fn active_file(&mut self) -> Result<(u64, Arc<File>)> {
+        if self.file.is_none() {
+            let (gen, file) = self.new_log()?;
+            let mut manifest = self.manifest.clone();
+            manifest.gens.push(gen);
+            manifest.last_version = self.next_version - 1;
+            manifest.publish(&self.dir)?;
+            self.manifest = manifest;
+            self.segments.insert(gen, SegmentStats::of_file(&file.metadata()?));
+            self.file = Some((gen, Arc::new(file)));
         }
-        return Ok(());
+        Ok(self.file.clone().expect("self.file"))
     }
 
-    panic!("No compaction detected");
-}
+    /// Creates an empty log file, which is ignored until it is published.
+    fn new_log(&mut self) -> Result<(u64, File)> {
+        let gen = self.next_gen;
+        self.next_gen += 1;
+        let fpath = self.dir.log_path(gen);
+        debug!("Creating file at {}", fpath.display());
+        let file = OpenOptions::new().write(true).create_new(true).open(fpath)?;
+        Ok((gen, file))
+    }
 
+    fn write_command(&mut self, cmd: Command) -> Result<()> {
+        let serialized = record::encode(&bincode::serialize(&cmd)?);
+        let length = serialized.len() as u64;
+        let (gen, offset) = self.append(&serialized)?;
+        self.next_version = cmd.version + 1;
+        self.apply(gen, offset, length, &cmd);
+        Ok(())
+    }
 
+    /// Writes all operations of `batch` as one record, whose value is the
+    /// records of the single operations. Replay either finds the record
+    /// intact and applies all of them, or none of them.
+    fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+        let version = self.next_version;
+        let written_at = now_millis();
+        // only the last operation on each key matters
+        let ops: BTreeMap<&[u8], Option<&[u8]>> = batch.ops().collect();
+        let mut records = Vec::new();
+        // (command, offset in `records`, length)
+        let mut commands = Vec::with_capacity(ops.len());
+        for (key, value) in ops {
+            let typ = match value {
+                Some(_) => CommandType::Set,
+                None if self.reader.pointer(key).is_some() => CommandType::Remove,
+                None => continue,
+            };
+            let cmd = Command { typ, key, value: value.unwrap_or(&[]), version, written_at };
+            let record = record::encode(&bincode::serialize(&cmd)?);
+            commands.push((cmd, records.len() as u64, record.len() as u64));
+            records.extend(record);
+        }
+        if commands.is_empty() {
+            return Ok(());
+        }
 
+        let batch = Command { typ: CommandType::Batch, key: &[], value: &records, version, written_at };
+        let payload = bincode::serialize(&batch)?;
+        let start = record::HEADER_LEN + Command::decode(&payload)?.value_offset(&payload) as u64;
+        let serialized = record::encode(&payload);
+        let (gen, offset) = self.append(&serialized)?;
+        self.next_version = version + 1;
+        debug!("Wrote a batch of {} commands to {}", commands.len(), self.dir.log_name(gen));
+
+        // only the records inside are ever read or copied by compaction
+        let mut overhead = serialized.len() as u64;
+        for (cmd, inner_offset, length) in commands {
+            self.apply(gen, offset + start + inner_offset, length, &cmd);
+            overhead -= length;
+        }
+        if let Some(stats) = self.segments.get_mut(&gen) {
+            stats.dead += overhead;
+        }
+        Ok(())
+    }
 
+    /// Appends a record to the active log file, rolling over to a new one
+    /// if it is full. Returns where the record was written.
+    fn append(&mut self, serialized: &[u8]) -> Result<(u64, u64)> {
+        let length = serialized.len() as u64;
+        let (mut gen, mut file) = self.active_file()?;
+        let mut offset = file.as_ref().seek(SeekFrom::End(0))?;
+        if offset > 0 && offset + length > self.options.max_segment_size {
+            debug!("{} is full, rolling over", self.dir.log_name(gen));
+            self.seal()?;
+            (gen, file) = self.active_file()?;
+            offset = 0;
+        }
+
+        file.as_ref().write_all(serialized)?;
+        self.syncer.appended(&file)?;
+        if let Some(stats) = self.segments.get_mut(&gen) {
+            stats.total += length;
+        }
+        Ok((gen, offset))
+    }
+
+    /// Updates the index, the history, the merge chains and the hints for a
+    /// command written at `offset`.
+    fn apply(&mut self, gen: u64, offset: u64, length: u64, cmd: &Command) {
+        let expires_at = cmd.typ.expires_at();
+        let (version, written_at) = (cmd.version, cmd.written_at);
+        let mut lp = LogPointer { gen, offset, length, expires_at, version, written_at, merge: false };
+        let (tombstone, merge) = match cmd.typ {
+            CommandType::Set | CommandType::SetExpiring(_) => (false, false),
+            // tombstones only matter until compaction drops what they remove
+            CommandType::Remove => {
+                self.mark_dead(&lp);
+                (true, false)
+            }
+            CommandType::Merge => (false, true),
+            CommandType::Batch => unreachable!("batches are applied command by command"),
+        };
+        let key = cmd.key.to_vec();
+        self.hints.push(Hint { key: key.clone(), offset, length, tombstone, merge, expires_at, version, written_at });
+
+        let old_lp = self.index.read().unwrap().get(cmd.key).copied();
+        // an operand is folded into the current value rather than replacing it
+        let merged_into = old_lp.filter(|old| merge && !old.is_expired(now_millis()));
+        let replaced = if merged_into.is_some() { None } else { old_lp };
+        let replaced_chain = match replaced {
+            Some(old_lp) if old_lp.merge => {
+                let merges = self.reader.merges.read().unwrap();
+                Some(Arc::new(merges.get(cmd.key).expect("operands of a merged value").clone()))
+            }
+            _ => None,
+        };
+        if self.options.retention.is_enabled() {
+            let mut history = self.reader.history.write().unwrap();
+            let old_versions = history.entry(key.clone()).or_default();
+            if let Some(ref chain) = replaced_chain {
+                detach_chain(old_versions, chain);
+            }
+            // the value an operand is folded into is an older value as well
+            if let Some(lp) = merged_into.or(replaced) {
+                old_versions.push_back(OldVersion { lp, removed: false, chain: replaced_chain.clone() });
+            }
+            if tombstone {
+                old_versions.push_back(OldVersion { lp, removed: true, chain: None });
+            }
+        } else if let Some(old_lp) = replaced {
+            debug!("Adding to compaction potential: {}", old_lp.length);
+            match replaced_chain {
+                Some(ref chain) => chain.records().for_each(|lp| mark_dead(&mut self.segments, lp)),
+                None => self.mark_dead(&old_lp),
+            }
+        }
+
+        if merge {
+            lp.merge = true;
+            lp.expires_at = merged_into.and_then(|current| current.expires_at);
+            let mut merges = self.reader.merges.write().unwrap();
+            let merges = Arc::make_mut(&mut merges);
+            match merged_into {
+                Some(current) if current.merge => {
+                    merges.get_mut(cmd.key).expect("operands of a merged value").operands.push(lp);
+                }
+                base => {
+                    merges.insert(key.clone(), MergeChain { base, operands: vec![lp] });
+                }
+            }
+        }
+        {
+            let mut index = self.index.write().unwrap();
+            let index = Arc::make_mut(&mut index);
+            match cmd.typ {
+                CommandType::Set | CommandType::SetExpiring(_) | CommandType::Merge => {
+                    index.insert(key, lp);
+                }
+                CommandType::Remove => {
+                    index.remove(cmd.key);
+                }
+                CommandType::Batch => {}
+            }
+        }
+        if !merge && replaced_chain.is_some() {
+            Arc::make_mut(&mut self.reader.merges.write().unwrap()).remove(cmd.key);
+        }
+        if self.options.retention.is_enabled() {
+            self.prune_history(Some(cmd.key));
+        }
+    }
+
+    /// Finishes the active log file for good: it is synced, gets its hints
+    /// and is never appended to again, the next write starts a new one.
+    fn seal(&mut self) -> Result<()> {
+        if let Some((gen, file)) = self.file.take() {
+            self.syncer.rotate()?;
+            file.sync_all()?;
+            let hints = std::mem::take(&mut self.hints);
+            write_hints(&self.dir, gen, file.metadata()?.len(), hints)?;
+            debug!("Sealed {}", self.dir.log_name(gen));
+        }
+        Ok(())
+    }
+
+    fn mark_dead(&mut self, lp: &LogPointer) {
+        mark_dead(&mut self.segments, lp);
+    }
+
+    /// Drops the older values of `key`, or of every key, that the retention
+    /// policy no longer keeps.
+    fn prune_history(&mut self, key: Option<&[u8]>) {
+        let retention = self.options.retention;
+        let now = now_millis();
+        let index = self.index.read().unwrap();
+        let mut history = self.reader.history.write().unwrap();
+        match key {
+            Some(key) => {
+                if let Some(old_versions) = history.get_mut(key) {
+                    prune_history(old_versions, index.get(key), &retention, now, &mut self.segments);
+                    if old_versions.is_empty() {
+                        history.remove(key);
+                    }
+                }
+            }
+            None => {
+                for (key, old_versions) in history.iter_mut() {
+                    prune_history(old_versions, index.get(key), &retention, now, &mut self.segments);
+                }
+                history.retain(|_, old_versions| !old_versions.is_empty());
+            }
+        }
+    }
+
+    /// Metadata of every log file, oldest first.
+    fn segment_infos(&self) -> Vec<SegmentInfo> {
+        let active_gen = self.file.as_ref().map(|(gen, _)| *gen);
+        self.manifest
+            .gens
+            .iter()
+            .filter_map(|gen| self.segments.get(gen).map(|stats| stats.info(*gen, Some(*gen) != active_gen)))
+            .collect()
+    }
+
+    fn should_compact(&self) -> bool {
+        let dead: u64 = self.segments.values().map(|stats| stats.dead).sum();
+        let total: u64 = self.segments.values().map(|stats| stats.total).sum();
+        !self.compacting
+            && dead > self.options.compaction_threshold
+            && dead as f64 >= self.options.compaction_ratio * total as f64
+    }
+
+    /// Picks the dirtiest log files until at most `target` dead bytes would
+    /// be left in the others, and reserves a log file to compact them into.
+    ///
+    /// The active log file is sealed first if it is picked, so the next
+    /// write starts a new one.
+    fn start_compaction(&mut self, target: u64) -> Result<Option<CompactionJob>> {
+        if self.compacting {
+            return Ok(None);
+        }
+        if self.options.retention.is_enabled() {
+            // older values the retention window has let go of are garbage
+            self.prune_history(None);
+        }
+        // operands cannot be folded without a merge operator, so the log
+        // files holding them are left alone
+        let candidates: Vec<u64> = match self.options.merge_operator {
+            Some(_) => self.manifest.gens.clone(),
+            None => {
+                let history = self.reader.history.read().unwrap();
+                let merges = self.reader.merges.read().unwrap();
+                let older = history.values().flatten().filter_map(|old| old.chain.as_deref());
+                let merged: BTreeSet<u64> =
+                    merges.values().chain(older).flat_map(MergeChain::records).map(|lp| lp.gen).collect();
+                self.manifest.gens.iter().copied().filter(|gen| !merged.contains(gen)).collect()
+            }
+        };
+        let gens = pick_dirtiest(&candidates, &self.segments, target);
+        if gens.is_empty() {
+            return Ok(None);
+        }
+        if let Some((active_gen, _)) = self.file {
+            if gens.contains(&active_gen) {
+                self.seal()?;
+            }
+        }
+
+        // a tombstone has to stay while an older log file that is not
+        // compacted along with it may still hold the record it removes
+        let mut tombstone_gens = Vec::new();
+        for (i, gen) in self.manifest.gens.iter().enumerate() {
+            if gens.contains(gen) && self.manifest.gens[..i].iter().any(|older| !gens.contains(older)) {
+                tombstone_gens.push(*gen);
+            }
+        }
+
+        // the output takes the place of the newest compacted file: the
+        // records of a merged value up to there are folded into one record,
+        // the operands in newer log files still replay on top of it. Older
+        // merged values the retention policy keeps are folded the same way,
+        // longest first so the chain they are part of starts from that one
+        let position = |gen: u64| self.manifest.gens.iter().position(|g| *g == gen);
+        let output_position = position(*gens.last().expect("picked log files"));
+        let before_output = |lp: &LogPointer| position(lp.gen) <= output_position;
+        let mut folds = Vec::new();
+        {
+            let history = self.reader.history.read().unwrap();
+            let merges = self.reader.merges.read().unwrap();
+            let mut chains: Vec<(&Vec<u8>, &MergeChain)> = merges.iter().collect();
+            for (key, old_versions) in history.iter() {
+                for chain in old_versions.iter().filter_map(|old| old.chain.as_ref()) {
+                    if !chains.iter().any(|(_, other)| ptr::eq(*other, &**chain)) {
+                        chains.push((key, chain));
+                    }
+                }
+            }
+            for (key, chain) in chains {
+                if !chain.records().any(|lp| gens.contains(&lp.gen)) || !chain.base.iter().all(before_output) {
+                    continue;
+                }
+                let folded = chain.operands.iter().take_while(|lp| before_output(lp)).count();
+                let current = merges.get(key).is_some_and(|current| ptr::eq(current, chain));
+                let mut lengths: BTreeSet<usize> = history
+                    .get(key)
+                    .into_iter()
+                    .flatten()
+                    .filter(|old| old.lp.merge && old.chain.as_deref().map_or(current, |old| ptr::eq(old, chain)))
+                    .filter_map(|old| chain.operands[..folded].iter().position(|lp| lp.same_record(&old.lp)))
+                    .map(|i| i + 1)
+                    .collect();
+                lengths.insert(folded);
+                for &length in lengths.iter().rev().filter(|&&length| length > 0 || chain.base.is_some()) {
+                    let operands = chain.operands[..length].to_vec();
+                    folds.push((key.clone(), MergeChain { base: chain.base, operands }));
+                }
+            }
+        }
+
+        let (output_gen, output) = self.new_log()?;
+        self.compacting = true;
+        debug!("Compacting log files {:?} into {}", gens, self.dir.log_name(output_gen));
+        Ok(Some(CompactionJob {
+            dir: self.dir.clone(),
+            gens,
+            tombstone_gens,
+            folds,
+            output_gen,
+            output,
+        }))
+    }
+
+    /// Publishes the output of `job` and points the index at it.
+    ///
+    /// The output only replaces the sealed log files once it is on disk and
+    /// a manifest naming it instead of them has been published, so a crash at
+    /// any point leaves either the old or the new files in place. Keys
+    /// written while the job ran keep pointing at their newer records.
+    fn finish_compaction(
+        &mut self,
+        job: &CompactionJob,
+        moved: Vec<MovedRecord>,
+        folded: Vec<LogPointer>,
+        written: u64,
+    ) -> Result<()> {
+        self.dir.sync()?;
+        let mut stats = SegmentStats::of_file(&job.output.metadata()?);
+        stats.total = written;
+        // the output takes the place of the newest compacted file, so the
+        // tombstones it keeps still replay after what they remove
+        let newest = *job.gens.last().expect("compaction job without log files");
+        let mut gens = Vec::with_capacity(self.manifest.gens.len());
+        for &gen in &self.manifest.gens {
+            if gen == newest {
+                gens.push(job.output_gen);
+            } else if !job.gens.contains(&gen) {
+                gens.push(gen);
+            }
+        }
+        let mut manifest = Manifest::new(gens);
+        // the versions of dropped records are never handed out again
+        manifest.last_version = self.next_version - 1;
+        manifest.publish(&self.dir)?;
+        self.manifest = manifest;
+
+        {
+            let mut history = self.reader.history.write().unwrap();
+            let mut index = self.index.write().unwrap();
+            let index = Arc::make_mut(&mut index);
+            for (key, old_lp, new_lp) in moved {
+                let at_old = |lp: &LogPointer| lp.gen == old_lp.gen && lp.offset == old_lp.offset;
+                let current = index.get(&key).is_some_and(at_old);
+                let old_version = history
+                    .get_mut(&key)
+                    .and_then(|old_versions| old_versions.iter_mut().find(|old| at_old(&old.lp)));
+                match (new_lp, old_version) {
+                    (Some(new_lp), _) if current => {
+                        index.insert(key, new_lp);
+                    }
+                    (Some(new_lp), Some(old_version)) => old_version.lp = new_lp,
+                    // overwritten, removed or let go of while the job ran
+                    (Some(new_lp), None) => stats.dead += new_lp.length,
+                    (None, _) if current => {
+                        index.remove(&key);
+                    }
+                    (None, _) => {}
+                }
+            }
+
+            // older merged values the retention policy keeps are now the
+            // records they were folded to
+            let keep_history = self.options.retention.is_enabled();
+            let mut merges = self.reader.merges.write().unwrap();
+            let merges = Arc::make_mut(&mut merges);
+            let folds: Vec<_> = job.folds.iter().zip(folded).collect();
+            // whether a folded record is the value of a key or an older one,
+            // rather than only the start of a merge chain
+            let mut owned = vec![false; folds.len()];
+            for (i, ((key, fold), new_lp)) in folds.iter().enumerate() {
+                let newest = match fold.operands.last() {
+                    Some(newest) => newest,
+                    None => continue,
+                };
+                let current = merges.get(key);
+                for old in history.get_mut(key).into_iter().flatten() {
+                    let chain = old.chain.as_deref().or(current);
+                    let folded = chain.is_some_and(|chain| chain.starts_with(fold));
+                    if old.lp.merge && old.lp.same_record(newest) && folded {
+                        if !job.gens.contains(&old.lp.gen) {
+                            mark_dead(&mut self.segments, &old.lp);
+                        }
+                        *old = OldVersion { lp: *new_lp, removed: false, chain: None };
+                        owned[i] = true;
+                    }
+                }
+            }
+
+            // a merge chain whose records were folded now starts from the
+            // folded record, unless it was replaced while the job ran
+            for (i, ((key, fold), new_lp)) in folds.iter().enumerate() {
+                let mut rebased = false;
+                if let Some(chain) = merges.get_mut(key).filter(|chain| chain.starts_with(fold)) {
+                    // with a retention policy, records are counted as dead
+                    // when the value they belong to is let go of
+                    if !keep_history {
+                        for lp in fold.records().filter(|lp| !job.gens.contains(&lp.gen)) {
+                            mark_dead(&mut self.segments, lp);
+                        }
+                    }
+                    chain.operands.drain(..fold.operands.len());
+                    if chain.operands.is_empty() {
+                        let replaced = index.insert(key.clone(), *new_lp);
+                        if let Some(old_lp) = replaced.filter(|lp| keep_history && !job.gens.contains(&lp.gen)) {
+                            mark_dead(&mut self.segments, &old_lp);
+                        }
+                        merges.remove(key);
+                        owned[i] = true;
+                    } else {
+                        chain.base = Some(*new_lp);
+                    }
+                    rebased = true;
+                }
+                if let Some(old_versions) = history.get_mut(key) {
+                    let old_chain =
+                        old_versions.iter().filter_map(|old| old.chain.clone()).find(|chain| chain.starts_with(fold));
+                    if let Some(old_chain) = old_chain {
+                        let operands = old_chain.operands[fold.operands.len()..].to_vec();
+                        let chain = Arc::new(MergeChain { base: Some(*new_lp), operands });
+                        for old in old_versions.iter_mut() {
+                            if old.chain.as_ref().is_some_and(|old| Arc::ptr_eq(old, &old_chain)) {
+                                old.chain = Some(Arc::clone(&chain));
+                            }
+                        }
+                    }
+                }
+                let live = if keep_history { owned[i] } else { rebased };
+                if !live {
+                    stats.dead += new_lp.length;
+                }
+            }
+        }
+        // readers pick their file handle under the index lock, so from here
+        // on only reads already in flight use the sealed files
+        for &gen in &job.gens {
+            self.reader.pool.retire(gen);
+            remove_hints(&self.dir, gen);
+            self.segments.remove(&gen);
+        }
+
+        self.segments.insert(job.output_gen, stats);
+        self.compacting = false;
+        debug!("Compacted {} log files into {}", job.gens.len(), self.dir.log_name(job.output_gen));
+        Ok(())
+    }
+
+    /// Gives up on `job`, leaving the sealed log files in place.
+    fn abort_compaction(&mut self, job: &CompactionJob) {
+        if !self.manifest.gens.contains(&job.output_gen) {
+            self.reader.pool.retire(job.output_gen);
+            remove_hints(&self.dir, job.output_gen);
+        }
+        self.compacting = false;
+    }
+}
+
+/// A key whose record was copied by a compaction, from where to where; or
+/// dropped because it expired.
+type MovedRecord = (Vec<u8>, LogPointer, Option<LogPointer>);
+
+/// A compaction started by the writer, which runs without the writer lock.
+struct CompactionJob {
+    dir: Arc<LogDir>,
+    // the sealed log files to rewrite, in manifest order
+    gens: Vec<u64>,
+    // those of them whose tombstones must be kept
+    tombstone_gens: Vec<u64>,
+    // the merged values with records in them, and the records to fold
+    folds: Vec<(Vec<u8>, MergeChain)>,
+    // the reserved log file to rewrite them into
+    output_gen: u64,
+    output: File,
+}
+
+impl CompactionJob {
+    /// Copies the live records of the sealed log files into the output file,
+    /// along with those the retention policy keeps, and folds the merged
+    /// values of `folds`. Returns where each of those was written.
+    ///
+    /// Only the index and history read locks are taken, briefly: nothing is appended to
+    /// sealed files, and they are only retired once the job is finished.
+    fn copy_live_records(&self, reader: &KvStoreReader) -> Result<(Vec<MovedRecord>, Vec<LogPointer>, u64)> {
+        let mut file = BufWriter::new(&self.output);
+        let mut moved = Vec::new();
+        let mut output_hints = Vec::new();
+        let mut written = 0;
+        let now = now_millis();
+        for &gen in &self.gens {
+            let sealed = reader.pool.get(gen)?;
+            let file_len = sealed.metadata()?.len();
+            let hints = match read_hints(&self.dir, gen, file_len)? {
+                Some(hints) => hints,
+                None => replay_log(File::open(self.dir.log_path(gen))?, gen, file_len)?.0,
+            };
+            for hint in hints {
+                // the records of merged values are folded below
+                if hint.merge {
+                    continue;
+                }
+                let at = |lp: &LogPointer| lp.gen == gen && lp.offset == hint.offset;
+                let live = {
+                    let index = reader.index.read().unwrap();
+                    if hint.tombstone {
+                        self.tombstone_gens.contains(&gen) && !index.contains_key(&hint.key)
+                    } else {
+                        index.get(&hint.key).is_some_and(at)
+                    }
+                };
+                // older values and removals the retention policy keeps
+                let kept = {
+                    let history = reader.history.read().unwrap();
+                    history.get(&hint.key).is_some_and(|old_versions| old_versions.iter().any(|old| at(&old.lp)))
+                };
+                if !live && !kept {
+                    continue;
+                }
+                let old_lp = LogPointer {
+                    gen,
+                    offset: hint.offset,
+                    length: hint.length,
+                    expires_at: hint.expires_at,
+                    version: hint.version,
+                    written_at: hint.written_at,
+                    merge: false,
+                };
+                // an expired value is dropped wherever a tombstone would be,
+                // otherwise it stays to hide older values of its key
+                if !hint.tombstone && !kept && old_lp.is_expired(now) && !self.tombstone_gens.contains(&gen) {
+                    moved.push((hint.key, old_lp, None));
+                    continue;
+                }
+
+                // records are copied as they are, only checked
+                let mut buf = vec![0; hint.length as usize];
+                read_exact_at(&sealed, &mut buf, hint.offset)?;
+                if record::decode(&buf).is_none() {
+                    return Err(KvsError::Corruption { gen, offset: hint.offset });
+                }
+                file.write_all(&buf)?;
+
+                let new_lp = LogPointer { gen: self.output_gen, offset: written, ..old_lp };
+                if !hint.tombstone || kept {
+                    moved.push((hint.key.clone(), old_lp, Some(new_lp)));
+                }
+                output_hints.push(Hint { offset: written, ..hint });
+                written += new_lp.length;
+            }
+        }
+
+        // each merged value is written as the value it folds to, with the
+        // version and the expiry time of its newest record
+        let mut folded = Vec::with_capacity(self.folds.len());
+        for (key, chain) in &self.folds {
+            let mut records = Vec::new();
+            for lp in chain.records() {
+                records.push((*lp, reader.pool.get(lp.gen)?));
+            }
+            let value = reader.fold(key, &records, chain.base.is_some())?;
+            let newest = records.last().expect("records to fold").0;
+            let cmd = Command {
+                typ: newest.expires_at.map_or(CommandType::Set, CommandType::SetExpiring),
+                key,
+                value: &value,
+                version: newest.version,
+                written_at: newest.written_at,
+            };
+            let record = record::encode(&bincode::serialize(&cmd)?);
+            file.write_all(&record)?;
+            let length = record.len() as u64;
+            push_hint(&mut output_hints, written, length, &cmd);
+            folded.push(LogPointer { gen: self.output_gen, offset: written, length, merge: false, ..newest });
+            written += length;
+        }
+
+        file.flush()?;
+        self.output.sync_all()?;
+        write_hints(&self.dir, self.output_gen, written, output_hints)?;
+        Ok((moved, folded, written))
+    }
+}
+
+fn mark_dead(segments: &mut HashMap<u64, SegmentStats>, lp: &LogPointer) {
+    if let Some(stats) = segments.get_mut(&lp.gen) {
+        stats.dead += lp.length;
+    }
+}
+
+/// Drops the oldest of `old_versions` while `retention` does not keep them,
+/// counting their records as dead. `current` is the value of their key.
+fn prune_history(
+    old_versions: &mut VecDeque<OldVersion>,
+    current: Option<&LogPointer>,
+    retention: &Retention,
+    now: u64,
+    segments: &mut HashMap<u64, SegmentStats>,
+) {
+    while let Some(oldest) = old_versions.front() {
+        let newer = old_versions.len() - 1 + current.is_some() as usize;
+        let replaced_at = match old_versions.get(1) {
+            Some(next) => next.lp.written_at,
+            None => current.map_or(now, |lp| lp.written_at),
+        };
+        // a removal with nothing before it tells no more than no history
+        if !oldest.removed && retention.keeps(newer, replaced_at, now) {
+            break;
+        }
+        // tombstones are counted as dead when written
+        if !oldest.removed {
+            mark_dead(segments, &oldest.lp);
+        }
+        old_versions.pop_front();
+    }
+}
+
+/// Keeps the merged older values in `old_versions` readable once `chain`,
+/// the merge chain of their key, is replaced.
+fn detach_chain(old_versions: &mut VecDeque<OldVersion>, chain: &Arc<MergeChain>) {
+    for old in old_versions.iter_mut().filter(|old| old.lp.merge && old.chain.is_none()) {
+        old.chain = Some(Arc::clone(chain));
+    }
+}
+
+/// Runs `job` to completion, only taking the writer lock to finish it.
+fn run_compaction(writer: &Mutex<KvStoreWriter>, reader: &KvStoreReader, job: CompactionJob) -> Result<()> {
+    let copied = job.copy_live_records(reader);
+    let mut writer = writer.lock().unwrap();
+    let finished = copied.and_then(|(moved, folded, written)| writer.finish_compaction(&job, moved, folded, written));
+    if finished.is_err() {
+        writer.abort_compaction(&job);
+    }
+    finished
+}
+
+/// Runs compactions on a background thread, one at a time.
+///
+/// The store is only closed once a running compaction is done: dropping the
+/// last clone waits for it, so the store can be reopened right away.
+#[derive(Default)]
+struct Compactor {
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Compactor {
+    fn spawn(&self, writer: Arc<Mutex<KvStoreWriter>>, reader: KvStoreReader, job: CompactionJob) {
+        let thread = thread::spawn(move || {
+            if let Err(e) = run_compaction(&writer, &reader, job) {
+                error!("Compaction failed: {}", e);
+            }
+        });
+        // the writer only starts a job once the previous one has finished
+        if let Some(previous) = self.thread.lock().unwrap().replace(thread) {
+            let _ = previous.join();
+        }
+    }
+
+    /// Waits for the running compaction, if any.
+    fn join(&self) {
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+impl KvStore {
+    /// Opens the store in `dpath` with the default `KvStoreOptions`.
+    pub fn open(dpath: &Path) -> Result<KvStore> {
+        KvStoreOptions::new().open(dpath)
+    }
+
+    pub(crate) fn open_with_options(dpath: &Path, options: &KvStoreOptions) -> Result<KvStore> {
+        let dpath_full = dpath.join(&options.data_dir);
+        let restored = if options.read_only {
+            None
+        } else {
+            restore_legacy_compaction(&dpath_full, &options.log_prefix)?
+        };
+        let exists = LogDir::new(dpath_full.clone(), options.log_prefix.clone()).exists()?;
+        if exists && options.error_if_exists {
+            return Err(KvsError::StoreExists(dpath_full));
+        }
+        if !exists && (options.read_only || !options.create_if_missing) {
+            return Err(KvsError::StoreNotFound(dpath_full));
+        }
+        if !dpath_full.exists() {
+            create_dir_all(&dpath_full)?;
+        }
+        // 对路径进行规范化
+        let dir = Arc::new(LogDir::new(dpath_full.canonicalize()?, options.log_prefix.clone()));
+
+        debug!("Opening KvStore, dpath: '{}'", dir.path().display());
+        let lock = match restored {
+            Some(lock) => lock,
+            None => DirLock::acquire(&dir, options.read_only)?,
+        };
+        if !options.read_only {
+            remove_legacy_compaction(&dpath_full)?;
+        }
+
+        let manifest = load_manifest(&dir, options.read_only)?;
+        let recovered = build_index(&dir, &manifest, options)?;
+        // keep appending to the newest log file unless it is sealed or full
+        let mut hints = Vec::new();
+        let file = match (manifest.gens.last().copied(), recovered.tail) {
+            (Some(gen), Some(tail)) if !options.read_only => {
+                let file_len = recovered.segments[&gen].total;
+                if file_len < options.max_segment_size {
+                    hints = tail;
+                    Some((gen, Arc::new(OpenOptions::new().write(true).open(dir.log_path(gen))?)))
+                } else {
+                    write_hints(&dir, gen, file_len, tail)?;
+                    None
+                }
+            }
+            _ => None,
+        };
+        let next_gen = dir_next_gen(&dir, &manifest)?;
+        let index = Arc::new(RwLock::new(Arc::new(recovered.index)));
+        let syncer = Syncer::new(options.sync);
+        let reader = KvStoreReader {
+            index: index.clone(),
+            history: Arc::new(RwLock::new(recovered.history)),
+            merges: Arc::new(RwLock::new(Arc::new(recovered.merges))),
+            merge_operator: options.merge_operator.clone(),
+            pool: Arc::new(FilePool::new(dir.clone(), options.max_open_files)),
+            _pinned: None,
+        };
+        let writer = KvStoreWriter {
+            _lock: lock,
+            dir,
+            options: options.clone(),
+            index,
+            reader: reader.clone(),
+            syncer: syncer.clone(),
+            next_gen,
+            next_version: recovered.last_version + 1,
+            manifest,
+            file,
+            hints,
+            segments: recovered.segments,
+            compacting: false,
+        };
+
+        Ok(KvStore {
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+            syncer,
+            compactor: Arc::new(Compactor::default()),
+        })
+    }
+
+    /// Runs `f` under the writer lock, then waits until its writes are
+    /// durable if the `SyncPolicy` asks for it.
+    ///
+    /// Compaction is started here once enough of the log is stale, and runs
+    /// in the background while writes continue.
+    fn with_writer<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<T>,
+    {
+        let (value, ticket, job) = {
+            let mut writer = self.writer.lock().unwrap();
+            if writer.options.read_only {
+                return Err(KvsError::ReadOnly);
+            }
+            let value = f(&mut writer)?;
+            let job = if writer.should_compact() {
+                // leave some garbage behind rather than rewriting clean files
+                let target = writer.options.compaction_threshold / 2;
+                writer.start_compaction(target)?
+            } else {
+                None
+            };
+            (value, self.syncer.ticket(), job)
+        };
+        if let Some(job) = job {
+            self.compactor.spawn(self.writer.clone(), self.reader.clone(), job);
+        }
+        self.syncer.wait(ticket)?;
+        Ok(value)
+    }
+
+    /// Metadata of the log files, oldest first.
+    ///
+    /// Every segment but the newest one is sealed, compaction replaces
+    /// sealed segments with new ones.
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        self.writer.lock().unwrap().segment_infos()
+    }
+
+    /// Iterates over the pairs with keys in `range`, in key order.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Scan {
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        Scan::new(self.reader.clone(), start, end)
+    }
+
+    /// Iterates over the pairs with keys starting with `prefix`, in key order.
+    pub fn prefix<K: AsRef<[u8]>>(&self, prefix: K) -> Scan {
+        let prefix = prefix.as_ref();
+        Scan::new(self.reader.clone(), Bound::Included(prefix.to_vec()), prefix_end(prefix))
+    }
+
+    /// Iterates over all pairs, in key order.
+    pub fn iter(&self) -> Scan {
+        Scan::new(self.reader.clone(), Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Takes a consistent, read-only view of the store as it is now.
+    ///
+    /// The snapshot shares the index with the store, the next write copies
+    /// it once. Values are not copied.
+    pub fn snapshot(&self) -> Snapshot {
+        let index = self.reader.index.read().unwrap();
+        let merges = self.reader.merges.read().unwrap();
+        let mut gens: BTreeSet<u64> = index.values().map(|lp| lp.gen).collect();
+        gens.extend(merges.values().flat_map(MergeChain::records).map(|lp| lp.gen));
+        // compaction only retires log files once the index no longer points
+        // into them, so pinning under the index lock cannot come too late
+        let pinned = self.reader.pool.pin(gens.into_iter().collect());
+        Snapshot {
+            reader: KvStoreReader {
+                index: Arc::new(RwLock::new(Arc::clone(&index))),
+                history: Arc::default(),
+                merges: Arc::new(RwLock::new(Arc::clone(&merges))),
+                merge_operator: self.reader.merge_operator.clone(),
+                pool: self.reader.pool.clone(),
+                _pinned: Some(Arc::new(pinned)),
+            },
+        }
+    }
+
+    /// Applies all writes of `batch` atomically: after a crash, either all
+    /// of them are there or none of them are.
+    pub fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.with_writer(|writer| writer.write_batch(batch))
+    }
+
+    /// Runs `f` in a transaction, which is committed if `f` succeeds.
+    ///
+    /// Returns `KvsError::Conflict` without writing anything if a key read or
+    /// written by `f` was changed concurrently. Nothing is retried.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction<KvStore>) -> Result<T>,
+    {
+        let mut txn = self.begin()?;
+        let value = f(&mut txn)?;
+        txn.commit()?;
+        Ok(value)
+    }
+
+    /// Starts a transaction that reads from a `Snapshot` taken now, so a
+    /// read of a key written since does not fail right away. The commit
+    /// still fails with `KvsError::Conflict` if a key read was changed.
+    pub fn begin_snapshot(&self) -> Transaction<KvStore> {
+        // no write can land while the writer lock is held, so the snapshot
+        // holds exactly the writes up to `start`
+        let writer = self.writer.lock().unwrap();
+        let start = writer.next_version - 1;
+        let snapshot = self.snapshot();
+        drop(writer);
+        Transaction::with_snapshot(self.clone(), start, snapshot)
+    }
+
+    /// Sets the value of a key, which expires after `ttl`.
+    ///
+    /// Expired keys are gone for every read right away, their records are
+    /// dropped from disk by compaction.
+    pub fn set_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_after(ttl);
+        self.with_writer(|writer| writer.set(key.as_ref(), value.as_ref(), Some(expires_at)))
+    }
+
+    /// Makes an existing key expire after `ttl`.
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not found.
+    pub fn expire<K: AsRef<[u8]>>(&self, key: K, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_after(ttl);
+        self.with_writer(|writer| writer.set_expiry(key.as_ref(), Some(expires_at))).map(|_| ())
+    }
+
+    /// Keeps an existing key from expiring, returns whether it was going to.
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not found.
+    pub fn persist<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        self.with_writer(|writer| writer.set_expiry(key.as_ref(), None)).map(|old| old.is_some())
+    }
+
+    /// Writes an operand for the merge operator set with
+    /// `KvStoreOptions::merge_operator` to fold into the value of a key.
+    ///
+    /// The operand is appended to the log as is, and only folded when the
+    /// key is read or its records are compacted. A merged value keeps the
+    /// expiry time of the value it was merged into. Returns
+    /// `KvsError::NoMergeOperator` if no merge operator is set.
+    pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, operand: V) -> Result<()> {
+        self.with_writer(|writer| writer.merge(key.as_ref(), operand.as_ref()))
+    }
+
+    /// Time left until a key expires, `None` if it never does.
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not found.
+    pub fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>> {
+        let key = key.as_ref();
+        let now = now_millis();
+        match self.reader.index.read().unwrap().get(key) {
+            Some(lp) if !lp.is_expired(now) => Ok(lp.expires_at.map(|at| Duration::from_millis(at - now))),
+            _ => Err(KvsError::NonExistentKey(String::from_utf8_lossy(key).into_owned())),
+        }
+    }
+
+    /// The values of a key that are kept, oldest first and ending with the
+    /// current one, or with its removal. The current value is listed even if
+    /// it has expired.
+    ///
+    /// Older values are only kept as far as `KvStoreOptions::keep_versions`
+    /// and `KvStoreOptions::keep_history_for` ask for.
+    pub fn history<K: AsRef<[u8]>>(&self, key: K) -> Result<Vec<Revision>> {
+        let key = key.as_ref();
+        let versions = self.reader.versions(key)?;
+        versions.into_iter().map(|(lp, stored)| self.reader.read_revision(key, &lp, &stored)).collect()
+    }
+
+    /// Gets the value a key had at `at`.
+    ///
+    /// Returns `None` if it had none then, or if its history is not kept as
+    /// far back. A value that had expired by then is `None` as well.
+    pub fn get_at<K: AsRef<[u8]>>(&self, key: K, at: At) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        let mut versions = self.reader.versions(key)?;
+        let found = versions.iter().rposition(|(lp, _)| match at {
+            At::Version(version) => lp.version <= version,
+            At::Time(time) => lp.written_at <= millis(time),
+        });
+        let i = match found {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        // as of a version, a value lasts until the next one is written
+        let until = match at {
+            At::Version(_) => versions.get(i + 1).map_or_else(now_millis, |(next, _)| next.written_at),
+            At::Time(time) => millis(time),
+        };
+        let (lp, stored) = versions.swap_remove(i);
+        if lp.is_expired(until) {
+            return Ok(None);
+        }
+        Ok(self.reader.read_revision(key, &lp, &stored)?.value)
+    }
+
+    /// Every key in the store, in order.
+    ///
+    /// Like the other key queries this is answered from the in-memory index,
+    /// no value is read from disk.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        let now = now_millis();
+        let index = self.reader.index.read().unwrap();
+        index.iter().filter(|(_, lp)| !lp.is_expired(now)).map(|(key, _)| key.clone()).collect()
+    }
+
+    /// The keys matching a glob `pattern`, in order.
+    ///
+    /// `*` matches any bytes, `?` one byte, `[a-z]` and `[^a-z]` a byte of a
+    /// class and `\` escapes the next byte.
+    pub fn keys_matching<P: AsRef<[u8]>>(&self, pattern: P) -> Vec<Vec<u8>> {
+        let pattern = pattern.as_ref();
+        let prefix = literal_prefix(pattern);
+        let end = prefix_end(&prefix);
+        let now = now_millis();
+        let index = self.reader.index.read().unwrap();
+        index
+            .range::<[u8], _>((Bound::Included(prefix.as_slice()), end.as_ref().map(Vec::as_slice)))
+            .filter(|(key, lp)| !lp.is_expired(now) && glob_match(pattern, key))
+            .map(|(key, _)| key)
+            .cloned()
+            .collect()
+    }
+
+    /// Number of keys in the store.
+    pub fn len(&self) -> usize {
+        let now = now_millis();
+        self.reader.index.read().unwrap().values().filter(|lp| !lp.is_expired(now)).count()
+    }
+
+    /// Whether the store holds no keys.
+    pub fn is_empty(&self) -> bool {
+        let now = now_millis();
+        !self.reader.index.read().unwrap().values().any(|lp| !lp.is_expired(now))
+    }
+
+    /// Whether `key` is in the store.
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        let index = self.reader.index.read().unwrap();
+        index.get(key.as_ref()).is_some_and(|lp| !lp.is_expired(now_millis()))
+    }
+
+    /// Compacts the log on the calling thread.
+    #[cfg(test)]
+    fn compact(&self) -> Result<()> {
+        self.compactor.join();
+        let job = self.writer.lock().unwrap().start_compaction(0)?;
+        match job {
+            Some(job) => run_compaction(&self.writer, &self.reader, job),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for a compaction running in the background.
+    #[cfg(test)]
+    pub(crate) fn wait_for_compaction(&self) {
+        self.compactor.join();
+    }
+}
+
+/// The expiry time of a value written now that lives for `ttl`.
+fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// State rebuilt from the log files when a store is opened.
+struct Recovered {
+    index: BTreeMap<Vec<u8>, LogPointer>,
+    // older values the retention policy keeps
+    history: History,
+    // what merged values are folded from
+    merges: Merges,
+    // byte counts of every log file
+    segments: HashMap<u64, SegmentStats>,
+    // highest version of any record written so far
+    last_version: u64,
+    // hints of the newest log file, if it was not sealed yet
+    tail: Option<Vec<Hint>>,
+}
+
+/// Rebuilds the index from the log files `gens`, reporting progress after
+/// each of them.
+///
+/// Sealed log files are read through their hints, checking only the end of
+/// the log file, unless `verify_on_open` is set. The others are replayed, and get hints
+/// unless they are the newest one or the store is opened read-only. A torn
+/// record at the end of the newest log file is cut off so later appends stay
+/// reachable, unless the store is opened read-only. Any other damage is never
+/// cut off but reported as `KvsError::Corruption`.
+fn build_index(dir: &LogDir, manifest: &Manifest, options: &KvStoreOptions) -> Result<Recovered> {
+    let read_only = options.read_only;
+    let gens = &manifest.gens;
+    // hints written in an older format cannot be read anymore
+    let use_hints = !options.verify_on_open && manifest.is_current();
+    let now = now_millis();
+    let mut last_version = manifest.last_version;
+    // 建立索引
+    let mut index = BTreeMap::new();
+    // older values are pruned once all log files are read, as the retention
+    // window may have let go of some while the store was closed
+    let keep_history = options.retention.is_enabled();
+    let mut history = History::new();
+    let mut merges = Merges::new();
+    // version of the records that removed or expired the keys not in `index`
+    let mut removed_at = HashMap::new();
+    let mut segments = HashMap::new();
+    let mut tail = None;
+    // (gen, offset, file length) of a torn record at the end of a file
+    let mut torn = None;
+
+    let mut progress = OpenProgress {
+        segment: 0,
+        from_hints: false,
+        segments_done: 0,
+        segments_total: gens.len(),
+        bytes_done: 0,
+        bytes_total: 0,
+    };
+    for &gen in gens {
+        progress.bytes_total += fs::metadata(dir.log_path(gen))?.len();
+    }
+
+    for (i, &gen) in gens.iter().enumerate() {
+        let file = File::open(dir.log_path(gen))?;
+        let metadata = file.metadata()?;
+        segments.insert(gen, SegmentStats::of_file(&metadata));
+        if let Some((torn_gen, offset, _)) = torn {
+            // older files were synced before the next one was started, so a
+            // crash cannot have cut them short
+            return Err(KvsError::Corruption { gen: torn_gen, offset });
+        }
+
+        let file_len = metadata.len();
+        let hints = if use_hints { read_hints(dir, gen, file_len)? } else { None };
+        let (hints, replayed) = match hints {
+            Some(hints) => (hints, false),
+            None => {
+                debug!("Replaying {}", dir.log_name(gen));
+                let (hints, torn_at) = replay_log(file, gen, file_len)?;
+                torn = torn_at.map(|offset| (gen, offset, file_len));
+                (hints, true)
+            }
+        };
+
+        for hint in &hints {
+            let lp = LogPointer {
+                gen,
+                offset: hint.offset,
+                length: hint.length,
+                expires_at: hint.expires_at,
+                version: hint.version,
+                written_at: hint.written_at,
+                merge: hint.merge,
+            };
+            last_version = last_version.max(hint.version);
+            // compaction keeps older values the retention policy keeps in
+            // the place of newer log files, so records of a key are put in
+            // order by version rather than by where they are
+            let newest = index.get(&hint.key).map(|lp: &LogPointer| lp.version).or_else(|| removed_at.get(&hint.key).copied());
+            // compaction writes merged values again as plain records with the
+            // same version, which take the place of what they were folded
+            // from; records from before versions all have version 0
+            let rewrites = |old: &LogPointer| hint.version > 0 && old.version == hint.version;
+            if newest.is_some_and(|newest| newest > hint.version) {
+                if hint.tombstone || hint.merge || !keep_history {
+                    mark_dead(&mut segments, &lp);
+                }
+                if keep_history && !hint.merge {
+                    let old_versions: &mut VecDeque<OldVersion> = history.entry(hint.key.clone()).or_default();
+                    let at = old_versions.partition_point(|old| old.lp.version <= hint.version);
+                    let old = OldVersion { lp, removed: hint.tombstone, chain: None };
+                    match at.checked_sub(1).map(|i| &mut old_versions[i]) {
+                        Some(rewritten) if rewrites(&rewritten.lp) => {
+                            mark_dead(&mut segments, &rewritten.lp);
+                            *rewritten = old;
+                        }
+                        _ => old_versions.insert(at, old),
+                    }
+                }
+                continue;
+            }
+
+            let current = index.get(&hint.key).copied();
+            let rewritten = current.as_ref().is_some_and(rewrites);
+            // an operand is folded into the current value rather than
+            // replacing it, and expires along with it
+            let merged_into = current.filter(|current| hint.merge && !current.is_expired(now));
+            let replaced = if merged_into.is_some() { None } else { current };
+            let replaced_chain = match replaced {
+                Some(old_ptr) if old_ptr.merge => {
+                    Some(Arc::new(merges.remove(&hint.key).expect("operands of a merged value")))
+                }
+                _ => None,
+            };
+            if keep_history {
+                let old_versions = history.entry(hint.key.clone()).or_default();
+                if let Some(ref chain) = replaced_chain {
+                    detach_chain(old_versions, chain);
+                }
+                match merged_into.or(replaced) {
+                    Some(old_ptr) if rewritten => mark_dead(&mut segments, &old_ptr),
+                    Some(old_ptr) => {
+                        let chain = replaced_chain.clone();
+                        old_versions.push_back(OldVersion { lp: old_ptr, removed: false, chain });
+                    }
+                    None => {}
+                }
+                if hint.tombstone {
+                    old_versions.push_back(OldVersion { lp, removed: true, chain: None });
+                }
+            } else if let Some(old_ptr) = replaced {
+                debug!("Overridden command can be compacted: {}", old_ptr.length);
+                match replaced_chain {
+                    Some(ref chain) => chain.records().for_each(|lp| mark_dead(&mut segments, lp)),
+                    None => mark_dead(&mut segments, &old_ptr),
+                }
+            }
+
+            if hint.merge {
+                let mut lp = lp;
+                lp.expires_at = merged_into.and_then(|current| current.expires_at);
+                match merged_into {
+                    Some(current) if current.merge => {
+                        merges.get_mut(&hint.key).expect("operands of a merged value").operands.push(lp);
+                    }
+                    base => {
+                        merges.insert(hint.key.clone(), MergeChain { base, operands: vec![lp] });
+                    }
+                }
+                removed_at.remove(&hint.key);
+                index.insert(hint.key.clone(), lp);
+            } else if hint.tombstone || lp.is_expired(now) {
+                // values that expired while the store was closed are gone
+                mark_dead(&mut segments, &lp);
+                removed_at.insert(hint.key.clone(), hint.version);
+                index.remove(&hint.key);
+            } else {
+                removed_at.remove(&hint.key);
+                index.insert(hint.key.clone(), lp);
+            }
+        }
+
+        // batches take a few more bytes than the records inside them
+        let end = match torn {
+            Some((torn_gen, offset, _)) if torn_gen == gen => offset,
+            _ => file_len,
+        };
+        let covered: u64 = hints.iter().map(|hint| hint.length).sum();
+        let stats = segments.get_mut(&gen).expect("stats of the file being read");
+        stats.total += end;
+        stats.dead += end.saturating_sub(covered);
+
+        if replayed && i + 1 == gens.len() {
+            tail = Some(hints);
+        } else if replayed && !read_only {
+            write_hints(dir, gen, file_len, hints)?;
+        }
+
+        progress.segment = gen;
+        progress.from_hints = !replayed;
+        progress.segments_done += 1;
+        progress.bytes_done += file_len;
+        info!(
+            "Read {} ({}/{} log files, {}/{} bytes)",
+            dir.log_name(gen),
+            progress.segments_done,
+            progress.segments_total,
+            progress.bytes_done,
+            progress.bytes_total
+        );
+        if let Some(ref callback) = options.progress {
+            callback.report(&progress);
+        }
+    }
+
+    for (key, old_versions) in history.iter_mut() {
+        prune_history(old_versions, index.get(key), &options.retention, now, &mut segments);
+    }
+    history.retain(|_, old_versions| !old_versions.is_empty());
+
+    match torn {
+        Some((torn_gen, offset, _)) if read_only => {
+            warn!("Ignoring torn record at the end of {}, offset {}", dir.log_name(torn_gen), offset);
+        }
+        Some((torn_gen, offset, file_len)) => {
+            let file = OpenOptions::new().write(true).open(dir.log_path(torn_gen))?;
+            file.set_len(offset)?;
+            file.sync_all()?;
+            warn!(
+                "Truncated {} bytes of a torn record from the end of {}",
+                file_len - offset,
+                dir.log_name(torn_gen)
+            );
+        }
+        None => {}
+    }
+
+    Ok(Recovered {
+        index,
+        history,
+        merges,
+        segments,
+        tail,
+        last_version,
+    })
+}
+
+/// Reads every record of log file `gen` into hints, along with the offset
+/// of a torn record at its end.
+fn replay_log(file: File, gen: u64, file_len: u64) -> Result<(Vec<Hint>, Option<u64>)> {
+    let mut scanner = RecordScanner::new(BufReader::new(file), file_len);
+    let mut hints = Vec::new();
+    loop {
+        let (offset, length, payload) = match scanner.next()? {
+            Scanned::Record { offset, length, payload } => (offset, length, payload),
+            Scanned::End => return Ok((hints, None)),
+            Scanned::Torn { offset } => return Ok((hints, Some(offset))),
+            Scanned::Corrupt { offset } => return Err(KvsError::Corruption { gen, offset }),
+        };
+        let cmd = Command::decode(&payload)?;
+        if let CommandType::Batch = cmd.typ {
+            // the records inside are checked by the record around them
+            let start = offset + record::HEADER_LEN + cmd.value_offset(&payload) as u64;
+            let mut inner = RecordScanner::new(cmd.value, cmd.value.len() as u64);
+            loop {
+                match inner.next()? {
+                    Scanned::Record { offset, length, payload } => {
+                        push_hint(&mut hints, start + offset, length, &Command::decode(&payload)?)
+                    }
+                    Scanned::End => break,
+                    Scanned::Torn { offset } | Scanned::Corrupt { offset } => {
+                        return Err(KvsError::Corruption { gen, offset: start + offset })
+                    }
+                }
+            }
+            continue;
+        }
+        push_hint(&mut hints, offset, length, &cmd);
+    }
+}
+
+fn push_hint(hints: &mut Vec<Hint>, offset: u64, length: u64, cmd: &Command) {
+    let (tombstone, merge) = match cmd.typ {
+        CommandType::Set | CommandType::SetExpiring(_) => (false, false),
+        CommandType::Remove => (true, false),
+        CommandType::Merge => (false, true),
+        CommandType::Batch => unreachable!("batches do not nest"),
+    };
+    let expires_at = cmd.typ.expires_at();
+    let (version, written_at) = (cmd.version, cmd.written_at);
+    hints.push(Hint { key: cmd.key.to_vec(), offset, length, tombstone, merge, expires_at, version, written_at });
+}
+
+/// Loads the manifest and removes the log and hint files it does not list.
+///
+/// Those are either the output of a compaction that was never published, or
+/// files a published compaction did not get to remove. Stores from before
+/// the manifest existed adopt all their log files, which are framed first if
+/// they were written before records had a header.
+fn load_manifest(dir: &LogDir, read_only: bool) -> Result<Manifest> {
+    let manifest = match Manifest::load(dir)? {
+        Some(manifest) => manifest,
+        None => {
+            let gens = dir.list_gens()?;
+            let mut framed = false;
+            for &gen in &gens {
+                if unframed::is_unframed(&dir.log_path(gen))? {
+                    // read-only stores cannot rewrite their log files
+                    if read_only {
+                        return Err(KvsError::IncompatibleFormat(0));
+                    }
+                    unframed::frame_log(&dir.log_path(gen))?;
+                    framed = true;
+                }
+            }
+            if framed {
+                dir.sync()?;
+            }
+            let manifest = Manifest::new(gens);
+            if !read_only {
+                manifest.publish(dir)?;
+            }
+            return Ok(manifest);
+        }
+    };
+
+    if !read_only {
+        for gen in dir.list_gens()? {
+            if !manifest.gens.contains(&gen) {
+                warn!("Removing {}, left behind by an interrupted compaction", dir.log_name(gen));
+                fs::remove_file(dir.log_path(gen))?;
+            }
+        }
+        for gen in dir.list_hints()? {
+            if !manifest.gens.contains(&gen) || !manifest.is_current() {
+                remove_hints(dir, gen);
+            }
+        }
+        if !manifest.is_current() {
+            // the log files are still readable, their hints are rewritten
+            // as the index is rebuilt
+            info!("Upgrading {} to the current format", dir.path().display());
+            let manifest = Manifest::new(manifest.gens);
+            manifest.publish(dir)?;
+            return Ok(manifest);
+        }
+    }
+    Ok(manifest)
+}
+
+/// The generation after every log file in the directory, published or not.
+fn dir_next_gen(dir: &LogDir, manifest: &Manifest) -> Result<u64> {
+    let newest = dir.list_gens()?.into_iter().chain(manifest.gens.iter().copied()).max();
+    Ok(newest.map_or(1, |gen| gen + 1))
+}
+
+/// The `<dpath>.new` and `<dpath>.old` directories the old compaction
+/// scheme swapped the store through.
+fn legacy_compaction_dirs(dpath: &Path) -> (PathBuf, PathBuf) {
+    let sibling = |suffix: &str| {
+        let mut path = dpath.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    (sibling(".new"), sibling(".old"))
+}
+
+/// Moves the store back from `<dpath>.old`, where the old compaction scheme
+/// left it if it died between its two renames, and returns its lock.
+///
+/// The store is locked before it is moved. The lock file moves along with
+/// it, so the lock still holds the store once it is back at `dpath`.
+fn restore_legacy_compaction(dpath: &Path, prefix: &str) -> Result<Option<DirLock>> {
+    let (_, old_dpath) = legacy_compaction_dirs(dpath);
+    if dpath.exists() || !old_dpath.exists() {
+        return Ok(None);
+    }
+    let lock = DirLock::acquire(&LogDir::new(old_dpath.clone(), prefix.to_owned()), false)?;
+    // another process may have moved it back before we got the lock
+    if !dpath.exists() {
+        // the new files were never synced
+        warn!("Restoring {} after an interrupted compaction", old_dpath.display());
+        fs::rename(&old_dpath, dpath)?;
+    }
+    Ok(Some(lock))
+}
+
+/// Removes what the old compaction scheme left next to the store, which has
+/// to be locked by now.
+fn remove_legacy_compaction(dpath: &Path) -> Result<()> {
+    let (new_dpath, old_dpath) = legacy_compaction_dirs(dpath);
+    if new_dpath.exists() {
+        fs::remove_dir_all(&new_dpath)?;
+    }
+    if old_dpath.exists() {
+        fs::remove_dir_all(&old_dpath)?;
+    }
+    Ok(())
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        self.with_writer(|writer| writer.set(key.as_ref(), value.as_ref(), None))
+    }
+
+    fn get_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        debug!("Getting key {:?}", String::from_utf8_lossy(key.as_ref()));
+        self.reader.read_value(key.as_ref())
+    }
+
+    fn get_bytes_into<K: AsRef<[u8]>>(&self, key: K, buf: &mut Vec<u8>) -> Result<bool> {
+        self.reader.read_value_into(key.as_ref(), buf)
+    }
+
+    fn remove_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        self.with_writer(|writer| writer.remove(key.as_ref()))
+    }
+
+    fn get_versioned<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(Vec<u8>, u64)>> {
+        self.reader.read_versioned(key.as_ref())
+    }
+
+    fn incr_by<K: AsRef<[u8]>>(&self, key: K, delta: i64) -> Result<i64> {
+        self.with_writer(|writer| writer.incr_by(key.as_ref(), delta))
+    }
+
+    fn merge_bytes<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, operand: V) -> Result<()> {
+        self.merge(key, operand)
+    }
+
+    fn compare_and_swap<K, E, N>(&self, key: K, expected: Option<E>, new: Option<N>) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+        E: AsRef<[u8]>,
+        N: AsRef<[u8]>,
+    {
+        let (expected, new) = (expected.as_ref().map(AsRef::as_ref), new.as_ref().map(AsRef::as_ref));
+        self.with_writer(|writer| writer.compare_and_swap(key.as_ref(), expected, new))
+    }
+
+    fn set_if_version<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V, version: u64) -> Result<bool> {
+        self.with_writer(|writer| writer.set_if_version(key.as_ref(), value.as_ref(), version))
+    }
+
+    fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        self.write(batch)
+    }
+
+    fn last_version(&self) -> Result<u64> {
+        Ok(self.writer.lock().unwrap().next_version - 1)
+    }
+
+    fn commit_transaction(&self, reads: &BTreeMap<Vec<u8>, Option<u64>>, start: u64, batch: &WriteBatch) -> Result<()> {
+        self.with_writer(|writer| writer.commit_transaction(reads, start, batch))
+    }
+
+    fn contains_key_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        Ok(self.contains_key(key))
+    }
+
+    fn keys_matching(&self, pattern: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(KvStore::keys_matching(self, pattern))
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn scan_bytes(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, after: Option<&[u8]>, limit: usize) -> Result<ScanPage> {
+        let scan = self.range::<&[u8], _>((start, end));
+        match after {
+            Some(after) => scan.after(after).page(limit),
+            None => scan.page(limit),
+        }
+    }
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    assert!(store.remove("key1").is_ok());
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove("key1").is_err());
+    Ok(())
+}
+
+
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+
+    println!("{:?}", store.reader.index);
+
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    println!("\nDropping store");
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn overwrite_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    store.set("key1", "value2")?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    store.set("key1", "value3")?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..10 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+
+
+
+
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    fn assert_send_sync<T: Clone + Send + Sync>() {}
+    assert_send_sync::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i);
+                    store.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}-{}", t, i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_readers_share_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    // every key lives in the same log file, so readers race on one handle
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for round in 0..20 {
+                    for i in (0..100).rev().skip((t + round) % 7) {
+                        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    // flip the last byte of the first value
+    let fpath = temp_dir.path().join(".kvs").join("log-1");
+    let mut bytes = fs::read(&fpath)?;
+    let pos = bytes.windows(6).position(|w| w == b"value1").expect("value should be on disk");
+    bytes[pos + 5] ^= 1;
+    fs::write(&fpath, &bytes)?;
+
+    match store.get("key1") {
+        Err(KvsError::Corruption { gen: 1, offset: 0 }) => {}
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen: 1, offset: 0 }) => {}
+        Err(e) => panic!("expected corruption, got {:?}", e),
+        Ok(_) => panic!("expected corruption"),
+    }
+    Ok(())
+}
+
+/// A record as the first versions of the store wrote it: the bincode of
+/// `(command: u32, key: String, value: String)`, with no header.
+#[cfg(test)]
+fn unframed_record(command: u32, key: &str, value: &str) -> Vec<u8> {
+    let mut record = command.to_le_bytes().to_vec();
+    for field in &[key, value] {
+        record.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        record.extend_from_slice(field.as_bytes());
+    }
+    record
+}
+
+#[test]
+fn upgrade_unframed_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join(".kvs");
+    fs::create_dir(&data_dir)?;
+    let (set, remove) = (0, 1);
+    let mut first = Vec::new();
+    first.extend(unframed_record(set, "key1", "value1"));
+    first.extend(unframed_record(set, "key2", "value2"));
+    first.extend(unframed_record(set, "key1", "value3"));
+    first.extend(unframed_record(remove, "key2", ""));
+    fs::write(data_dir.join("log-1"), &first)?;
+    // the last record was cut short by a crash
+    let mut second = unframed_record(set, "key3", "value4");
+    second.extend(&unframed_record(set, "key4", "lost")[..10]);
+    fs::write(data_dir.join("log-2"), &second)?;
+
+    // read-only stores cannot upgrade
+    assert!(matches!(
+        KvStoreOptions::new().read_only(true).open(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat(0))
+    ));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key3")?, Some("value4".to_owned()));
+        assert_eq!(store.get("key4")?, None);
+        Ok(())
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get_versioned("key1")?.map(|(_, version)| version), Some(0));
+    store.set("key5", "value5")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get("key5")?, Some("value5".to_owned()));
+    assert!(fs::read(data_dir.join("log-1"))? != first);
+    Ok(())
+}
+
+#[test]
+fn truncate_torn_tail_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+
+    // simulate a crash halfway through appending a third record
+    let fpath = temp_dir.path().join(".kvs").join("log-1");
+    let valid_len = fs::metadata(&fpath)?.len();
+    let bytes = fs::read(&fpath)?;
+    let mut file = OpenOptions::new().append(true).open(&fpath)?;
+    file.write_all(&bytes[..bytes.len() / 3])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&fpath)?.len(), valid_len);
+    store.set("key3", "value3")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn refuse_to_truncate_damage_before_the_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    drop(store);
+
+    // a flipped bit makes the first record run past the end of the file
+    let fpath = temp_dir.path().join(".kvs").join("log-1");
+    let mut bytes = fs::read(&fpath)?;
+    bytes[2] ^= 1;
+    fs::write(&fpath, &bytes)?;
+
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Corruption { gen: 1, offset: 0 })));
+    assert_eq!(fs::read(&fpath)?, bytes);
+
+    // nor is a damaged last record in a log file other than the newest
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(256).verify_on_open(true).clone();
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let fpath = temp_dir.path().join(".kvs").join("log-1");
+    let mut bytes = fs::read(&fpath)?;
+    *bytes.last_mut().unwrap() ^= 1;
+    fs::write(&fpath, &bytes)?;
+    assert!(matches!(options.open(temp_dir.path()), Err(KvsError::Corruption { gen: 1, .. })));
+    assert_eq!(fs::read(&fpath)?, bytes);
+    Ok(())
+}
+
+#[test]
+fn sync_policies() -> Result<()> {
+    use crate::durability::SyncPolicy;
+    use std::time::Duration;
+
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(5)),
+        SyncPolicy::GroupCommit,
+    ];
+    for policy in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new().sync_policy(*policy).open(temp_dir.path())?;
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let store = store.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for i in 0..25 {
+                        store.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                    }
+                    store.remove(format!("key{}-0", t))
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        drop(store);
+        let store = KvStoreOptions::new().sync_policy(*policy).open(temp_dir.path())?;
+        for t in 0..4 {
+            assert_eq!(store.get(format!("key{}-0", t))?, None);
+            for i in 1..25 {
+                assert_eq!(store.get(format!("key{}-{}", t, i))?, Some(format!("value{}", i)));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn lock_out_second_process() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    // flock conflicts between open file descriptions, even in one process
+    let holder = format!("process {}", std::process::id());
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked(ref locked_by)) if *locked_by == holder => {}
+        Err(e) => panic!("expected Locked, got {:?}", e),
+        Ok(_) => panic!("expected Locked"),
+    }
+    match KvStoreOptions::new().read_only(true).open(temp_dir.path()) {
+        Err(KvsError::Locked(_)) => {}
+        _ => panic!("expected Locked"),
+    }
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err(), "clones keep the lock");
+    drop(clone);
+
+    // inspection tools can share the store with each other, but not a writer
+    let reader1 = KvStoreOptions::new().read_only(true).open(temp_dir.path())?;
+    let reader2 = KvStoreOptions::new().read_only(true).open(temp_dir.path())?;
+    assert_eq!(reader1.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(reader2.get("key1")?, Some("value1".to_owned()));
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(reader1);
+    drop(reader2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    // a store with another prefix in the same directory has its own lock
+    let other = KvStoreOptions::new().log_prefix("other-").open(temp_dir.path())?;
+    other.set("key1", "other")?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(KvStoreOptions::new().log_prefix("other-").open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+#[test]
+fn compaction_is_published_atomically() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join(".kvs");
+    let store = KvStoreOptions::new().max_segment_size(128).open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set(format!("key{}", i), format!("value{}", i + 1))?;
+    }
+    let old_logs = LogDir::new(data_dir.clone(), "log-".to_owned()).list_gens()?;
+    assert!(old_logs.len() > 1);
+    store.compact()?;
+    drop(store);
+
+    // only the compacted log is left, next to its manifest
+    let dir = LogDir::new(data_dir.clone(), "log-".to_owned());
+    let manifest = Manifest::load(&dir)?.expect("manifest should be published");
+    assert_eq!(dir.list_gens()?, manifest.gens);
+    assert!(old_logs.iter().all(|gen| !manifest.gens.contains(gen)));
+
+    // a crash before publishing leaves an orphan log, a crash after it
+    // leaves a retired one behind: neither may be read
+    fs::write(dir.log_path(old_logs[0]), record::encode(b"stale"))?;
+    fs::write(dir.log_path(manifest.gens[0] + 1), record::encode(b"orphan"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i + 1)));
+    }
+    assert_eq!(dir.list_gens()?, manifest.gens);
+    Ok(())
+}
+
+#[test]
+fn recover_from_legacy_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // the old scheme died after moving the store aside to `.kvs.old`, its
+    // log files written by the first versions of the store
+    let old_dir = temp_dir.path().join(".kvs.old");
+    fs::create_dir(&old_dir)?;
+    fs::write(old_dir.join("log-1"), unframed_record(0, "key1", "value1"))?;
+    let new_dir = temp_dir.path().join(".kvs.new");
+    fs::create_dir(&new_dir)?;
+    fs::write(new_dir.join("log-1"), &unframed_record(0, "key1", "value1")[..7])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(!old_dir.exists());
+    assert!(!new_dir.exists());
+
+    // nothing is touched while another process has the store open
+    fs::create_dir(&old_dir)?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked(_))));
+    assert!(old_dir.exists());
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    assert!(!old_dir.exists());
+    Ok(())
+}
+
+#[test]
+fn write_during_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0")?;
+
+    // writes land in a new log file while the sealed ones are rewritten
+    let job = store.writer.lock().unwrap().start_compaction(0)?.expect("compaction should start");
+    let in_flight = store.reader.pool.get(job.gens[0])?;
+    store.set("key1", "newer")?;
+    store.remove("key2")?;
+    store.set("key10", "value10")?;
+    run_compaction(&store.writer, &store.reader, job)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key1")?, Some("newer".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+        for i in 3..11 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // the sealed log file stays around until the last read on it is done
+    let sealed = temp_dir.path().join(".kvs").join("log-1");
+    assert!(sealed.exists());
+    drop(in_flight);
+    assert!(!sealed.exists());
+
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn compact_while_writing_concurrently() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new().compaction_threshold(4096).open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for round in 0..100 {
+                    for i in 0..10 {
+                        let key = format!("key{}-{}", t, i);
+                        store.set(key.clone(), format!("value{}", round))?;
+                        assert_eq!(store.get(key)?, Some(format!("value{}", round)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..4 {
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}-{}", t, i))?, Some("value99".to_owned()));
+        }
+    }
+    let logs = LogDir::new(temp_dir.path().join(".kvs"), "log-".to_owned()).list_gens()?.len();
+    assert!(logs < 10, "stale log files should be removed, found {}", logs);
+    Ok(())
+}
+
+#[test]
+fn compact_dirtiest_segments_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join(".kvs");
+    let options = KvStoreOptions::new().max_segment_size(256).clone();
+    let store = options.open(temp_dir.path())?;
+    store.set("gone", "soon")?;
+    for i in 0..10 {
+        store.set(format!("cold{}", i), format!("value{}", i))?;
+    }
+    for round in 0..20 {
+        store.set("hot", format!("value{}", round))?;
+    }
+    store.remove("gone")?;
+    store.set("hot", "last")?;
+
+    // leave the barely dirty first log file alone
+    let (first_dead, job) = {
+        let mut writer = store.writer.lock().unwrap();
+        let first_dead = writer.segments[&1].dead;
+        (first_dead, writer.start_compaction(first_dead)?.expect("compaction should start"))
+    };
+    assert!(first_dead > 0);
+    assert!(!job.gens.contains(&1));
+    assert!(!job.tombstone_gens.is_empty(), "the tombstone of `gone` has to stay");
+    let (compacted, last_gen) = (job.gens.clone(), *job.gens.last().unwrap());
+    run_compaction(&store.writer, &store.reader, job)?;
+
+    assert!(data_dir.join("log-1").exists());
+    assert!(compacted.iter().all(|gen| !data_dir.join(format!("log-{}", gen)).exists()));
+    assert!(!store.writer.lock().unwrap().segments.contains_key(&last_gen));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("gone")?, None);
+        assert_eq!(store.get("hot")?, Some("last".to_owned()));
+        for i in 0..10 {
+            assert_eq!(store.get(format!("cold{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&options.open(temp_dir.path())?)
+}
+
+#[test]
+fn roll_over_and_seal_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(256).clone();
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0", "newer")?;
+
+    let segments = store.segments();
+    assert!(segments.len() > 1, "segments should roll over");
+    let (active, sealed) = segments.split_last().unwrap();
+    assert!(!active.sealed);
+    assert!(sealed.iter().all(|segment| segment.sealed));
+    for segment in &segments {
+        let fpath = temp_dir.path().join(".kvs").join(format!("log-{}", segment.id));
+        assert_eq!(fs::metadata(fpath)?.len(), segment.size);
+        assert!(segment.size <= 256);
+    }
+    assert!(segments[0].live_bytes < segments[0].size, "key0 was overwritten");
+    assert!(segments[1..].iter().all(|segment| segment.live_bytes == segment.size));
+
+    // the newest segment is appended to again after reopening, unless full
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.segments(), segments);
+    store.set("key20", "value20")?;
+    let reopened = store.segments();
+    assert_eq!(reopened.len(), segments.len());
+    assert_eq!(reopened.last().unwrap().id, active.id);
+
+    drop(store);
+    let store = KvStoreOptions::new().max_segment_size(1).open(temp_dir.path())?;
+    store.set("key21", "value21")?;
+    let segments = store.segments();
+    assert!(segments[..segments.len() - 1].iter().all(|segment| segment.sealed));
+    assert_eq!(segments.len(), reopened.len() + 1);
+    Ok(())
+}
+
+#[test]
+fn open_from_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join(".kvs");
+    let options = KvStoreOptions::new().max_segment_size(6000).clone();
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        // keeps the values away from the end of their log file
+        store.set(format!("filler{}", i), "x".repeat(5000))?;
+    }
+    store.remove("key1")?;
+    let segments = store.segments();
+    drop(store);
+
+    // only sealed log files have hints
+    let (active, sealed) = segments.split_last().unwrap();
+    assert!(sealed.iter().all(|segment| data_dir.join(format!("log-{}.hint", segment.id)).exists()));
+    assert!(!data_dir.join(format!("log-{}.hint", active.id)).exists());
+
+    // values of sealed log files are not read when opening
+    let fpath = data_dir.join("log-1");
+    let mut bytes = fs::read(&fpath)?;
+    let pos = bytes.windows(6).position(|w| w == b"value0").expect("value should be on disk");
+    bytes[pos + 5] ^= 1;
+    fs::write(&fpath, &bytes)?;
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.segments(), segments);
+    match store.get("key0") {
+        Err(KvsError::Corruption { gen: 1, offset: 0 }) => {}
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert_eq!(store.get("key1")?, None);
+    for i in 2..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    // without hints, the log files are replayed and the hints written again
+    fs::remove_file(data_dir.join("log-2.hint"))?;
+    fs::write(data_dir.join("log-3.hint"), b"garbage")?;
+    fs::write(data_dir.join("log-99.hint"), b"orphan")?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.segments(), segments);
+    assert!(data_dir.join("log-2.hint").exists());
+    assert!(!data_dir.join("log-99.hint").exists());
+    assert_eq!(store.get("key1")?, None);
+    for i in 2..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.segments(), segments);
+    Ok(())
+}
+
+#[test]
+fn report_progress_and_verify_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(256).clone();
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let segments = store.segments();
+    drop(store);
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let log = reports.clone();
+    options.clone().on_progress(move |progress| log.lock().unwrap().push(progress.clone())).open(temp_dir.path())?;
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports.len(), segments.len());
+    let total: u64 = segments.iter().map(|segment| segment.size).sum();
+    for (report, segment) in reports.iter().zip(&segments) {
+        assert_eq!(report.segment, segment.id);
+        assert_eq!(report.from_hints, segment.sealed);
+        assert_eq!(report.segments_total, segments.len());
+        assert_eq!(report.bytes_total, total);
+    }
+    assert_eq!(reports.last().unwrap().bytes_done, total);
+
+    // corruption in a sealed log file is caught up front, without verifying
+    // as long as it is in the part of the file its hints are checked against
+    let fpath = temp_dir.path().join(".kvs").join("log-1");
+    let mut bytes = fs::read(&fpath)?;
+    let pos = bytes.windows(6).position(|w| w == b"value0").expect("value should be on disk");
+    bytes[pos + 5] ^= 1;
+    fs::write(&fpath, &bytes)?;
+
+    for options in &[options.clone(), options.clone().verify_on_open(true).clone()] {
+        match options.open(temp_dir.path()) {
+            Err(KvsError::Corruption { gen: 1, offset: 0 }) => {}
+            Err(e) => panic!("expected corruption, got {:?}", e),
+            Ok(_) => panic!("expected corruption"),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = [0u8, 159, 146, 150];
+    let value: Vec<u8> = (0..=255).collect();
+    store.set_bytes(key, &value)?;
+    store.set_bytes(b"text", "value")?;
+    assert_eq!(store.get_bytes(key)?, Some(value.clone()));
+
+    // the String API is a view of the same data
+    assert_eq!(store.get("text")?, Some("value".to_owned()));
+    assert_eq!(store.get_bytes("text")?, Some(b"value".to_vec()));
+    match store.get(String::from_utf8_lossy(&key)) {
+        Ok(None) => {}
+        other => panic!("lossy key should not match, got {:?}", other),
+    }
+    store.set_bytes("binary", [0xff, 0xfe])?;
+    match store.get("binary") {
+        Err(KvsError::InvalidUtf8(_)) => {}
+        other => panic!("expected InvalidUtf8, got {:?}", other),
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key)?, Some(value));
+    store.remove_bytes(key)?;
+    assert_eq!(store.get_bytes(key)?, None);
+    match store.remove_bytes(key) {
+        Err(KvsError::NonExistentKey(_)) => {}
+        other => panic!("expected NonExistentKey, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn borrowed_keys_and_reused_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = String::from("key1");
+    store.set(&key, "value1")?;
+    store.set("key2", String::from("a longer value"))?;
+    assert_eq!(store.get(&key)?, Some("value1".to_owned()));
+
+    // lookups through a shared reference, reusing one buffer
+    let shared: &KvStore = &store;
+    let mut buf = Vec::with_capacity(64);
+    let capacity = buf.capacity();
+    assert!(shared.get_bytes_into("key2", &mut buf)?);
+    assert_eq!(buf, b"a longer value");
+    assert!(shared.get_bytes_into(b"key1", &mut buf)?);
+    assert_eq!(buf, b"value1");
+    assert_eq!(buf.capacity(), capacity);
+
+    assert!(!shared.get_bytes_into("key3", &mut buf)?);
+    assert_eq!(buf, b"value1");
+
+    shared.remove(&key)?;
+    assert!(!shared.get_bytes_into(&key, &mut buf)?);
+    Ok(())
+}
+
+#[test]
+fn range_and_prefix_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in (0..600).rev() {
+        store.set(format!("user:{:04}", i), format!("value{}", i))?;
+    }
+    store.set("admin", "root")?;
+    store.set_bytes([b'u', 0xff], "last")?;
+    store.remove("user:0001")?;
+
+    let keys = |scan: Scan| -> Result<Vec<Vec<u8>>> { scan.map(|pair| pair.map(|(key, _)| key)).collect() };
+    let all = keys(store.iter())?;
+    assert_eq!(all.len(), 601);
+    assert!(all.windows(2).all(|pair| pair[0] < pair[1]), "keys out of order");
+    assert_eq!(all[0], b"admin");
+
+    let (key, value) = store.prefix("user:").next().unwrap()?;
+    assert_eq!((key, value), (b"user:0000".to_vec(), b"value0".to_vec()));
+    assert_eq!(store.prefix("user:").count(), 599);
+    assert_eq!(store.prefix("user:05").count(), 100);
+    assert_eq!(keys(store.prefix([b'u', 0xff]))?, vec![vec![b'u', 0xff]]);
+    assert_eq!(keys(store.range("user:0000".."user:0003"))?, vec![b"user:0000".to_vec(), b"user:0002".to_vec()]);
+    assert_eq!(store.range("user:0590"..="user:0599").count(), 10);
+    assert_eq!(store.range("b".."a").count(), 0);
+    assert_eq!(store.range("user:0005".."user:0003").after("user:0004").count(), 0);
+
+    // page through every user, resuming after the last key of each page
+    let mut paged = Vec::new();
+    let mut resume = None;
+    loop {
+        let scan = store.prefix("user:");
+        let page = match resume {
+            Some(key) => scan.after(key).page(250)?,
+            None => scan.page(250)?,
+        };
+        paged.extend(page.pairs.into_iter().map(|(key, _)| key));
+        resume = page.resume;
+        if resume.is_none() {
+            break;
+        }
+    }
+    assert_eq!(paged, keys(store.prefix("user:"))?);
+
+    // the same through the engine interface the server uses
+    let page = store.scan_prefix_bytes(b"user:", Some(b"user:0597"), 10)?;
+    assert_eq!(page.pairs.len(), 2);
+    assert_eq!(page.resume, None);
+    let page = store.scan_bytes(Bound::Unbounded, Bound::Excluded(b"user:0001"), None, 1)?;
+    assert_eq!(page.pairs, vec![(b"admin".to_vec(), b"root".to_vec())]);
+    assert_eq!(page.resume, Some(b"admin".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn list_and_count_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.keys(), Vec::<Vec<u8>>::new());
+
+    store.set("user:2", "b")?;
+    store.set("user:1", "a")?;
+    store.set("user:10", "c")?;
+    store.set("admin", "root")?;
+    store.remove("user:2")?;
+    assert_eq!(store.len(), 3);
+    assert!(!store.is_empty());
+    assert_eq!(store.keys(), vec![b"admin".to_vec(), b"user:1".to_vec(), b"user:10".to_vec()]);
+    assert!(store.contains_key("user:1"));
+    assert!(!store.contains_key("user:2"));
+
+    assert_eq!(store.keys_matching("user:?"), vec![b"user:1".to_vec()]);
+    assert_eq!(store.keys_matching("user:*"), vec![b"user:1".to_vec(), b"user:10".to_vec()]);
+    assert_eq!(store.keys_matching("*n"), vec![b"admin".to_vec()]);
+    assert_eq!(store.keys_matching("*").len(), 3);
+    assert_eq!(store.key_count()?, 3);
+
+    // answered from the index, even with the values gone from disk
+    let fpath = temp_dir.path().join(".kvs").join("log-1");
+    let len = fs::metadata(&fpath)?.len();
+    fs::write(&fpath, vec![0; len as usize])?;
+    assert!(store.get("admin").is_err());
+    assert_eq!(store.keys().len(), 3);
+    assert!(store.contains_key_bytes("admin")?);
+    Ok(())
+}
+
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let hour = Duration::from_secs(3600);
+    store.set_with_ttl("session", "token", Duration::from_millis(50))?;
+    store.set_with_ttl("cache", "entry", hour)?;
+    store.set("user", "alice")?;
+    assert_eq!(store.get("session")?, Some("token".to_owned()));
+    assert_eq!(store.ttl("user")?, None);
+    assert!(store.ttl("cache")?.is_some_and(|ttl| ttl > hour - Duration::from_secs(60) && ttl <= hour));
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get("session")?, None);
+    assert!(!store.contains_key("session"));
+    assert_eq!(store.keys(), vec![b"cache".to_vec(), b"user".to_vec()]);
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.iter().count(), 2);
+    assert!(matches!(store.ttl("session"), Err(KvsError::NonExistentKey(_))));
+    assert!(matches!(store.remove("session"), Err(KvsError::NonExistentKey(_))));
+    assert!(matches!(store.expire("session", hour), Err(KvsError::NonExistentKey(_))));
+
+    store.expire("user", Duration::from_millis(50))?;
+    assert!(store.ttl("user")?.is_some());
+    assert!(store.persist("cache")?);
+    assert!(!store.persist("cache")?);
+    assert_eq!(store.ttl("cache")?, None);
+    store.set_with_ttl("later", "value", Duration::from_millis(50))?;
+    store.set("later", "value")?;
+    assert_eq!(store.ttl("later")?, None);
+
+    // expirations that pass while the store is closed are respected
+    drop(store);
+    thread::sleep(Duration::from_millis(100));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user")?, None);
+    assert_eq!(store.get("cache")?, Some("entry".to_owned()));
+    assert_eq!(store.keys(), vec![b"cache".to_vec(), b"later".to_vec()]);
+
+    // and compaction drops expired records
+    store.set("cache", "newer")?;
+    store.compact()?;
+    let mut on_disk = Vec::new();
+    for entry in fs::read_dir(temp_dir.path().join(".kvs"))? {
+        on_disk.extend(fs::read(entry?.path())?);
+    }
+    let contains = |needle: &[u8]| on_disk.windows(needle.len()).any(|window| window == needle);
+    assert!(!contains(b"alice") && !contains(b"token"), "expired records should be dropped");
+    assert!(contains(b"newer"));
+    Ok(())
+}
+
+#[test]
+fn upgrade_from_format_1() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(128).clone();
+    let store = options.open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // hints used to be written without expiry times
+    let dir = LogDir::new(temp_dir.path().join(".kvs"), "log-".to_owned());
+    let manifest = fs::read_to_string(dir.manifest_path())?.replace(&format!("\"format\":{}", FORMAT_VERSION), "\"format\":1");
+    fs::write(dir.manifest_path(), manifest)?;
+    for gen in dir.list_hints()? {
+        fs::write(dir.hint_path(gen), b"hints of an older format")?;
+    }
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let reported = progress.clone();
+    let store = options.clone().on_progress(move |p| reported.lock().unwrap().push(p.from_hints)).open(temp_dir.path())?;
+    assert!(progress.lock().unwrap().iter().all(|from_hints| !from_hints));
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(Manifest::load(&dir)?.expect("manifest").is_current());
+    drop(store);
+
+    // the hints were rewritten in the current format
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let reported = progress.clone();
+    options.clone().on_progress(move |p| reported.lock().unwrap().push(p.from_hints)).open(temp_dir.path())?;
+    assert!(progress.lock().unwrap().iter().filter(|from_hints| **from_hints).count() > 1);
+    Ok(())
+}
+
+#[test]
+fn conditional_writes_and_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_if_absent("key1", "value1")?);
+    assert!(!store.set_if_absent("key1", "value2")?);
+    let (value, version) = store.get_versioned("key1")?.unwrap();
+    assert_eq!(value, b"value1");
+
+    assert!(!store.compare_and_swap("key1", Some("value2"), Some("value3"))?);
+    assert!(store.compare_and_swap("key1", Some("value1"), Some("value3"))?);
+    let (_, newer) = store.get_versioned("key1")?.unwrap();
+    assert!(newer > version);
+    assert!(!store.set_if_version("key1", "value4", version)?);
+    assert!(store.set_if_version("key1", "value4", newer)?);
+    assert!(!store.set_if_version("missing", "value", 0)?);
+
+    // writing the same value still takes the version, so only one caller
+    // holding it succeeds
+    let (_, current) = store.get_versioned("key1")?.unwrap();
+    assert!(store.set_if_version("key1", "value4", current)?);
+    assert!(!store.set_if_version("key1", "value4", current)?);
+
+    // conditional writes keep the expiry time
+    store.set_with_ttl("ttl", "value1", Duration::from_secs(60))?;
+    assert!(store.compare_and_swap("ttl", Some("value1"), Some("value2"))?);
+    assert!(store.ttl("ttl")?.is_some());
+    let (_, ttl_version) = store.get_versioned("ttl")?.unwrap();
+    assert!(store.set_if_version("ttl", "value3", ttl_version)?);
+    assert!(store.ttl("ttl")?.is_some());
+
+    assert!(store.compare_and_swap("key1", Some("value4"), None::<&str>)?);
+    assert_eq!(store.get("key1")?, None);
+    assert!(store.compare_and_swap("key1", None::<&str>, None::<&str>)?);
+    assert!(store.compare_and_swap("key1", None::<&str>, Some("value5"))?);
+
+    // an atomic read-modify-write from many threads
+    store.set("counter", "0")?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let (value, version) = store.get_versioned("counter")?.unwrap();
+                        let next = String::from_utf8(value)?.parse::<u64>().unwrap() + 1;
+                        if store.set_if_version("counter", next.to_string(), version)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter")?, Some("100".to_owned()));
+
+    // versions keep growing across compactions and restarts, even when the
+    // newest records were dropped
+    store.set("gone", "value")?;
+    let (_, gone_version) = store.get_versioned("gone")?.unwrap();
+    store.remove("gone")?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("gone", "again")?;
+    assert!(store.get_versioned("gone")?.unwrap().1 > gone_version + 1);
+    Ok(())
+}
+
+#[test]
+fn read_records_without_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join(".kvs");
+    fs::create_dir(&dir)?;
+    let mut log = Vec::new();
+    for (typ, key, value) in [
+        (CommandType::Set, &b"key1"[..], &b"value1"[..]),
+        (CommandType::Set, b"key2", b"value2"),
+        (CommandType::Remove, b"key2", b""),
+    ] {
+        log.extend(record::encode(&bincode::serialize(&(typ, key, value))?));
+    }
+    fs::write(dir.join("log-1"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value1".to_vec(), 0)));
+    assert_eq!(store.get("key2")?, None);
+    assert!(store.set_if_version("key1", "value2", 0)?);
+    assert!(store.get_versioned("key1")?.unwrap().1 > 0);
+    Ok(())
+}
+
+#[test]
+fn atomic_write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from", "100")?;
+    store.set("to", "0")?;
+
+    let mut batch = WriteBatch::new();
+    batch.put("from", "70").put("to", "30").put("log", "first").delete("missing");
+    batch.put("log", "moved 30").put("temp", "value").delete("temp");
+    assert_eq!(batch.len(), 7);
+    store.write(&batch)?;
+    assert_eq!(store.get("from")?, Some("70".to_owned()));
+    assert_eq!(store.get("to")?, Some("30".to_owned()));
+    assert_eq!(store.get("log")?, Some("moved 30".to_owned()));
+    assert_eq!(store.get("temp")?, None);
+    let version = store.get_versioned("from")?.unwrap().1;
+    assert_eq!(store.get_versioned("to")?.unwrap().1, version, "a batch shares one version");
+    store.write(&WriteBatch::new())?;
+
+    let mut batch = WriteBatch::new();
+    batch.delete("log").put("to", "40");
+    store.write_batch(&batch)?;
+    assert_eq!(store.get("log")?, None);
+
+    // a batch is replayed as a whole, and only counts its records as live
+    let segments = store.segments();
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.segments(), segments);
+    assert_eq!(store.keys(), vec![b"from".to_vec(), b"to".to_vec()]);
+    assert_eq!(store.get("to")?, Some("40".to_owned()));
+
+    // a crash halfway through writing a batch leaves none of it behind
+    let mut batch = WriteBatch::new();
+    batch.put("from", "0").put("to", "100");
+    store.write(&batch)?;
+    drop(store);
+    let fpath = temp_dir.path().join(".kvs").join("log-1");
+    let file = OpenOptions::new().write(true).open(&fpath)?;
+    file.set_len(file.metadata()?.len() - 5)?;
+    drop(file);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from")?, Some("70".to_owned()));
+    assert_eq!(store.get("to")?, Some("40".to_owned()));
+
+    store.compact()?;
+    assert_eq!(store.get("from")?, Some("70".to_owned()));
+    assert_eq!(store.segments().iter().map(|segment| segment.size - segment.live_bytes).sum::<u64>(), 0);
+    Ok(())
+}
+
+#[test]
+fn transactions_detect_conflicts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("alice", "100")?;
+    store.set("bob", "0")?;
+    fn balance(value: Option<Vec<u8>>) -> u64 {
+        String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+    }
+    fn transfer(txn: &mut Transaction<KvStore>, amount: u64) -> Result<()> {
+        let from = balance(txn.get("alice")?);
+        let to = balance(txn.get("bob")?);
+        txn.set("alice", (from - amount).to_string());
+        txn.set("bob", (to + amount).to_string());
+        Ok(())
+    }
+
+    store.transaction(|txn| transfer(txn, 30))?;
+    assert_eq!(store.get("alice")?, Some("70".to_owned()));
+    assert_eq!(store.get("bob")?, Some("30".to_owned()));
+
+    // a key read was changed before the commit
+    let mut txn = store.begin()?;
+    transfer(&mut txn, 10)?;
+    store.set("bob", "35")?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict(ref key)) if key == "bob"));
+    assert_eq!(store.get("alice")?, Some("70".to_owned()));
+
+    // a key written without reading it was changed after the start
+    let mut txn = store.begin()?;
+    txn.set("carol", "1");
+    store.set("carol", "2")?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict(_))));
+    assert_eq!(store.get("carol")?, Some("2".to_owned()));
+
+    // a missing key read was created meanwhile
+    let mut txn = store.begin()?;
+    assert_eq!(txn.get("dave")?, None);
+    txn.set("erin", "1");
+    store.set("dave", "1")?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict(_))));
+    assert_eq!(store.get("erin")?, None);
+
+    // a key first read after a concurrent commit changed it
+    let mut txn = store.begin()?;
+    assert_eq!(balance(txn.get("alice")?), 70);
+    store.write(WriteBatch::new().put("alice", "60").put("bob", "45"))?;
+    assert!(matches!(txn.get("bob"), Err(KvsError::Conflict(ref key)) if key == "bob"));
+
+    // unless reads see the store as it was when the transaction began
+    let mut txn = store.begin_snapshot();
+    assert_eq!(balance(txn.get("alice")?), 60);
+    store.write(WriteBatch::new().put("alice", "65").put("bob", "40"))?;
+    assert_eq!(balance(txn.get("bob")?), 45);
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict(_))));
+
+    // reads see the transaction's own writes
+    store.transaction(|txn| {
+        txn.set("frank", "1");
+        assert_eq!(txn.get("frank")?, Some(b"1".to_vec()));
+        txn.remove("frank")?;
+        assert_eq!(txn.get("frank")?, None);
+        assert!(matches!(txn.remove("frank"), Err(KvsError::NonExistentKey(_))));
+        txn.remove("carol")
+    })?;
+    assert_eq!(store.get("carol")?, None);
+    assert_eq!(store.get("frank")?, None);
+
+    // concurrent transfers, retried on conflicts, keep the total
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..10 {
+                    loop {
+                        match store.transaction(|txn| transfer(txn, 1)) {
+                            Err(KvsError::Conflict(_)) => continue,
+                            result => break result?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("alice")?, Some("25".to_owned()));
+    assert_eq!(store.get("bob")?, Some("80".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_from_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new().max_segment_size(256).open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    let snapshot = store.snapshot();
+    let log_files = || LogDir::new(temp_dir.path().join(".kvs"), "log-".to_owned()).list_gens();
+    let before = log_files()?;
+
+    // the index is shared until the store is written to
+    let shares_index = |snapshot: &Snapshot| {
+        Arc::ptr_eq(&store.reader.index.read().unwrap(), &snapshot.reader.index.read().unwrap())
+    };
+    assert!(shares_index(&snapshot));
+    store.set("key00", "newer")?;
+    assert!(!shares_index(&snapshot));
+
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), "newer")?;
+    }
+    store.remove("key00")?;
+    store.set("key20", "new key")?;
+    store.compact()?;
+    assert_eq!(store.get("key01")?, Some("newer".to_owned()));
+
+    // unaffected by the writes, and reading from log files compaction retired
+    assert_eq!(snapshot.get("key00")?, Some(b"value0".to_vec()));
+    assert_eq!(snapshot.get("key20")?, None);
+    let pairs: Vec<_> = snapshot.iter().collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 20);
+    assert_eq!(pairs[19], (b"key19".to_vec(), b"value19".to_vec()));
+    assert_eq!(snapshot.prefix("key1").count(), 10);
+    assert_eq!(snapshot.range("key05".."key07").count(), 2);
+    assert!(before.iter().all(|gen| log_files().unwrap().contains(gen)), "pinned log files were removed");
+
+    // a scan keeps the files of its snapshot around
+    let mut scan = snapshot.iter();
+    drop(snapshot);
+    assert_eq!(scan.next().unwrap()?, (b"key00".to_vec(), b"value0".to_vec()));
+    drop(scan);
+    let after = log_files()?;
+    assert!(before.iter().any(|gen| !after.contains(gen)), "retired log files should be removed");
+    Ok(())
+}
+
+#[test]
+fn keep_and_read_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(256).keep_versions(2).clone();
+    let store = options.open(temp_dir.path())?;
+    let mut versions = Vec::new();
+    for i in 1..=4 {
+        store.set("key", format!("value{}", i))?;
+        versions.push(store.get_versioned("key")?.unwrap().1);
+        for j in 0..5 {
+            store.set("junk", format!("junk{}-{}", i, j))?;
+        }
+    }
+    let values = |store: &KvStore| -> Result<Vec<Option<Vec<u8>>>> {
+        Ok(store.history("key")?.into_iter().map(|revision| revision.value).collect())
+    };
+    let value = |i| Some(format!("value{}", i).into_bytes());
+    assert_eq!(values(&store)?, vec![value(2), value(3), value(4)]);
+    assert_eq!(store.get_at("key", At::Version(versions[2]))?, value(3));
+    assert_eq!(store.get_at("key", At::Version(versions[2] + 1))?, value(3));
+    assert_eq!(store.get_at("key", At::Version(u64::MAX))?, value(4));
+    // the first value is no longer kept
+    assert_eq!(store.get_at("key", At::Version(versions[0]))?, None);
+
+    store.remove("key")?;
+    assert_eq!(values(&store)?, vec![value(3), value(4), None]);
+    assert_eq!(store.get_at("key", At::Version(u64::MAX))?, None);
+    assert_eq!(store.get_at("key", At::Version(versions[3]))?, value(4));
+    assert_eq!(store.history("missing")?, vec![]);
+
+    // compaction keeps what the history needs, reopening reads it back
+    store.compact()?;
+    assert_eq!(values(&store)?, vec![value(3), value(4), None]);
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(values(&store)?, vec![value(3), value(4), None]);
+    assert_eq!(store.get("key")?, None);
+    assert_eq!(store.get_at("key", At::Version(versions[2]))?, value(3));
+    store.set("key", "value5")?;
+    assert_eq!(values(&store)?, vec![value(4), None, value(5)]);
+    drop(store);
+
+    // without retention only the current value is left
+    let store = KvStoreOptions::new().max_segment_size(256).open(temp_dir.path())?;
+    assert_eq!(values(&store)?, vec![value(5)]);
+    assert_eq!(store.get_at("key", At::Version(versions[3]))?, None);
+    store.compact()?;
+    assert_eq!(store.get("key")?, Some("value5".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_history_as_of_a_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new().keep_history_for(Duration::from_secs(3600)).open(temp_dir.path())?;
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let mut times = Vec::new();
+    for i in 0..5 {
+        store.set("key", format!("value{}", i))?;
+        thread::sleep(Duration::from_millis(5));
+        times.push(SystemTime::now());
+        thread::sleep(Duration::from_millis(5));
+    }
+    let history = store.history("key")?;
+    assert_eq!(history.len(), 5);
+    assert!(history.windows(2).all(|pair| pair[0].written_at < pair[1].written_at));
+    assert!(history[0].written_at > before);
+
+    assert_eq!(store.get_at("key", At::Time(before))?, None);
+    for (i, time) in times.iter().enumerate() {
+        assert_eq!(store.get_at("key", At::Time(*time))?, Some(format!("value{}", i).into_bytes()));
+    }
+    // an expired value is gone as of when it expired
+    store.set_with_ttl("key", "brief", Duration::from_millis(20))?;
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.get_at("key", At::Time(SystemTime::now()))?, None);
+    assert_eq!(store.get_at("key", At::Version(u64::MAX))?, None);
+    assert_eq!(store.get_at("key", At::Time(times[4]))?, Some(b"value4".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn counters_and_merges() -> Result<()> {
+    use crate::merge::{Append, Max, SetUnion};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr_by("counter", 5)?, 5);
+    assert_eq!(store.decr_by("counter", 7)?, -2);
+    assert_eq!(store.get("counter")?, Some("-2".to_owned()));
+    store.set("name", "x")?;
+    assert!(matches!(store.incr_by("name", 1), Err(KvsError::NotAnInteger(_))));
+    store.set("counter", i64::MAX.to_string())?;
+    assert!(matches!(store.incr_by("counter", 1), Err(KvsError::NotAnInteger(_))));
+    assert!(matches!(store.decr_by("counter", i64::MIN), Err(KvsError::NotAnInteger(_))));
+    assert!(matches!(store.merge("log", "a"), Err(KvsError::NoMergeOperator)));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(256).merge_operator(Append::with_separator(",")).clone();
+    let store = options.open(temp_dir.path())?;
+    store.set("log", "a")?;
+    for i in 0..20 {
+        store.merge("log", i.to_string())?;
+        store.set("junk", format!("junk{}", i))?;
+    }
+    let expected = format!("a,{}", (0..20).map(|i| i.to_string()).collect::<Vec<_>>().join(","));
+    assert_eq!(store.get("log")?, Some(expected.clone()));
+    let snapshot = store.snapshot();
+    // operands written with no value to merge into start from nothing
+    store.merge("fresh", "x")?;
+    store.merge("fresh", "y")?;
+    assert_eq!(store.get("fresh")?, Some("x,y".to_owned()));
+
+    // compaction folds the operands into one record
+    store.compact()?;
+    store.merge("log", "z")?;
+    assert_eq!(store.get("log")?, Some(format!("{},z", expected)));
+    assert_eq!(snapshot.get("log")?, Some(expected.clone().into_bytes()));
+    drop(snapshot);
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("log")?, Some(format!("{},z", expected)));
+    assert_eq!(store.get("fresh")?, Some("x,y".to_owned()));
+
+    // a write replaces the merged value, a removal drops it
+    store.set("log", "b")?;
+    store.merge("log", "c")?;
+    store.remove("fresh")?;
+    assert_eq!(store.get("log")?, Some("b,c".to_owned()));
+    assert_eq!(store.get("fresh")?, None);
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("log")?, Some("b,c".to_owned()));
+    assert_eq!(store.get("fresh")?, None);
+    drop(store);
+
+    // merged values cannot be read without the operator
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.get("log"), Err(KvsError::NoMergeOperator)));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new().merge_operator(Max).open(temp_dir.path())?;
+    for operand in &["3", "12", "-4"] {
+        store.merge("max", operand)?;
+    }
+    assert_eq!(store.get("max")?, Some("12".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new().merge_operator(SetUnion::new(b',')).open(temp_dir.path())?;
+    for operand in &["b,a", "c", "a"] {
+        store.merge("set", operand)?;
+    }
+    assert_eq!(store.get("set")?, Some("a,b,c".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_history_of_merged_values() -> Result<()> {
+    use crate::merge::Append;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options =
+        KvStoreOptions::new().max_segment_size(256).keep_versions(20).merge_operator(Append::with_separator(",")).clone();
+    let store = options.open(temp_dir.path())?;
+    let writes: Vec<(&str, Option<&str>)> = vec![
+        ("set", Some("a")),
+        ("merge", Some("b")),
+        ("merge", Some("c")),
+        ("set", Some("x")),
+        ("merge", Some("y")),
+        ("remove", None),
+        ("merge", Some("z")),
+        ("merge", Some("w")),
+    ];
+    for (i, (write, value)) in writes.into_iter().enumerate() {
+        match (write, value) {
+            ("set", Some(value)) => store.set("log", value)?,
+            ("merge", Some(value)) => store.merge("log", value)?,
+            _ => store.remove("log")?,
+        }
+        for j in 0..3 {
+            store.set("junk", format!("junk{}-{}", i, j))?;
+        }
+    }
+    let value = |value: &str| Some(value.as_bytes().to_vec());
+    let expected = vec![value("a"), value("a,b"), value("a,b,c"), value("x"), value("x,y"), None, value("z"), value("z,w")];
+    let check = |store: &KvStore, expected: &[Option<Vec<u8>>]| -> Result<()> {
+        let history = store.history("log")?;
+        let values: Vec<_> = history.iter().map(|revision| revision.value.clone()).collect();
+        assert_eq!(values, expected);
+        for revision in &history {
+            assert_eq!(store.get_at("log", At::Version(revision.version))?, revision.value);
+        }
+        Ok(())
+    };
+    check(&store, &expected)?;
+
+    // compaction folds the older merged values as well
+    store.compact()?;
+    check(&store, &expected)?;
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    check(&store, &expected)?;
+
+    store.merge("log", "v")?;
+    store.set("junk", "more")?;
+    let mut expected = expected;
+    expected.push(value("z,w,v"));
+    store.compact()?;
+    check(&store, &expected)?;
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    check(&store, &expected)?;
+    assert_eq!(store.get("log")?, Some("z,w,v".to_owned()));
+    drop(store);
+
+    // older values the retention policy lets go of are still folded into
+    // newer ones
+    let store = options.clone().keep_versions(2).open(temp_dir.path())?;
+    check(&store, &expected[6..])?;
+    store.compact()?;
+    check(&store, &expected[6..])?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extension of the hint file next to a sealed log file.
+const HINT_EXTENSION: &str = ".hint";
+
+/// Suffix of the file listing the live log files, which starts with the
+/// log prefix so stores with different prefixes can share a directory.
+const MANIFEST_SUFFIX: &str = "MANIFEST";
+
+/// Suffix of the lock file, which starts with the log prefix for the same
+/// reason.
+const LOCK_SUFFIX: &str = "LOCK";
+
+/// Where the files of a store live and how they are named.
+#[derive(Debug)]
+pub(crate) struct LogDir {
+    path: PathBuf,
+    prefix: String,
+}
+
+impl LogDir {
+    pub(crate) fn new(path: PathBuf, prefix: String) -> LogDir {
+        LogDir { path, prefix }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// File name of log generation `gen`, e.g. `log-1`.
+    pub(crate) fn log_name(&self, gen: u64) -> String {
+        format!("{}{}", self.prefix, gen)
+    }
+
+    pub(crate) fn log_path(&self, gen: u64) -> PathBuf {
+        self.path.join(self.log_name(gen))
+    }
+
+    /// Path of the hints of log generation `gen`, e.g. `log-1.hint`.
+    pub(crate) fn hint_path(&self, gen: u64) -> PathBuf {
+        self.path.join(format!("{}{}", self.log_name(gen), HINT_EXTENSION))
+    }
+
+    /// Path of the manifest, e.g. `log-MANIFEST`.
+    pub(crate) fn manifest_path(&self) -> PathBuf {
+        self.path.join(format!("{}{}", self.prefix, MANIFEST_SUFFIX))
+    }
+
+    /// Path of the lock file, e.g. `log-LOCK`.
+    pub(crate) fn lock_path(&self) -> PathBuf {
+        self.path.join(format!("{}{}", self.prefix, LOCK_SUFFIX))
+    }
+
+    /// Generations of every log file in the directory, oldest first.
+    pub(crate) fn list_gens(&self) -> io::Result<Vec<u64>> {
+        self.list("")
+    }
+
+    /// Generations of every hint file in the directory, oldest first.
+    pub(crate) fn list_hints(&self) -> io::Result<Vec<u64>> {
+        self.list(HINT_EXTENSION)
+    }
+
+    /// Generations of the files named like `<prefix><gen><suffix>`.
+    fn list(&self, suffix: &str) -> io::Result<Vec<u64>> {
+        let mut gens = Vec::new();
+        if !self.path.exists() {
+            return Ok(gens);
+        }
+        for entry in fs::read_dir(&self.path)? {
+            let name = entry?.file_name();
+            let gen = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(self.prefix.as_str()))
+                .and_then(|name| name.strip_suffix(suffix));
+            if let Some(Ok(gen)) = gen.map(str::parse::<u64>) {
+                gens.push(gen);
+            }
+        }
+        gens.sort_unstable();
+        Ok(gens)
+    }
+
+    /// Whether a store has been created in the directory.
+    pub(crate) fn exists(&self) -> io::Result<bool> {
+        Ok(self.manifest_path().exists() || !self.list_gens()?.is_empty())
+    }
+
+    /// Flushes file creations, renames and removals in the directory.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        sync_dir(&self.path)
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// Directories cannot be opened as files on Windows, where renames are
+/// flushed with the metadata of the file itself.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -1,4 +1,30 @@
 pub mod kv;
 pub mod common;
+pub mod engine;
+pub mod server;
+pub mod merge;
+mod batch;
+mod durability;
+mod glob;
+mod hint;
+mod history;
+mod layout;
+mod lock;
+mod manifest;
+mod options;
+mod pool;
+mod record;
+mod unframed;
+mod segment;
+mod txn;
 
-
+pub use batch::WriteBatch;
+pub use durability::SyncPolicy;
+pub use engine::KvsEngine;
+pub use history::{At, Revision};
+pub use kv::{KvStore, KvsError, Result, Scan, ScanPage, Snapshot};
+pub use merge::MergeOperator;
+pub use options::{KvStoreOptions, OpenProgress};
+pub use segment::SegmentInfo;
+pub use server::KvsServer;
+pub use txn::Transaction;
@@ -0,0 +1,77 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+use fs2::FileExt;
+use log::debug;
+
+use crate::kv::{KvsError, Result};
+use crate::layout::LogDir;
+
+/// An advisory `flock` on the store in a data directory, released on drop.
+///
+/// Writers hold it exclusively and record their PID in the lock file, so a
+/// second process can tell who is in the way. Read-only stores share it.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Locks the store in `dir`, exclusively unless `shared` is set.
+    pub(crate) fn acquire(dir: &LogDir, shared: bool) -> Result<DirLock> {
+        let path = dir.lock_path();
+        let file = if shared {
+            // inspection tools may not be allowed to write to the directory
+            match File::open(&path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => create(&path)?,
+                opened => opened?,
+            }
+        } else {
+            create(&path)?
+        };
+
+        // called through the trait, newer toolchains have inherent `File` methods of the same name
+        let locked = if shared {
+            FileExt::try_lock_shared(&file)
+        } else {
+            FileExt::try_lock_exclusive(&file)
+        };
+        if let Err(e) = locked {
+            if e.kind() == fs2::lock_contended_error().kind() {
+                return Err(KvsError::Locked(holder(&file)));
+            }
+            return Err(e.into());
+        }
+
+        if !shared {
+            let mut file = &file;
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{}", process::id())?;
+        }
+        debug!("Locked {} ({})", path.display(), if shared { "shared" } else { "exclusive" });
+        Ok(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // closing the file releases the lock anyway
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+fn create(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+}
+
+/// Describes the process holding the lock, from the PID it left behind.
+fn holder(mut file: &File) -> String {
+    let mut pid = String::new();
+    match file.read_to_string(&mut pid).map(|_| pid.trim().parse::<u32>()) {
+        Ok(Ok(pid)) => format!("process {}", pid),
+        _ => "another process".to_owned(),
+    }
+}
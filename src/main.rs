@@ -1,26 +1,32 @@
 use clap::{App, Arg};
 use std::path::Path;
-use kv::{KvsError, KvStore, Result};
+use rust_kv::kv::{KvsError, KvStore, Result};
+use rust_kv::engine::KvsEngine;
 use tempfile::TempDir;
 use std::process::exit;
 
 use std::thread;
 
-pub mod kv;
-
 
 fn main() {
-    // let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    // let mut store = KvStore::open(temp_dir.path()).unwrap();
-    //
-    // for id in 0..=10 {
-    //     let key = format!("{}", id);
-    //     let value = format!("{}", id);
-    //     thread::spawn(move || {
-    //         store.set(key.clone(), value.clone())
-    //     });
-    // }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+
+    let handles: Vec<_> = (0..=10)
+        .map(|id| {
+            let store = store.clone();
+            let key = format!("{}", id);
+            let value = format!("{}", id);
+            thread::spawn(move || store.set(key, value))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap().unwrap();
+    }
 
+    for id in 0..=10 {
+        println!("{} => {:?}", id, store.get(format!("{}", id)).unwrap());
+    }
 }
 
 
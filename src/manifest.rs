@@ -0,0 +1,74 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kv::{KvsError, Result};
+use crate::layout::LogDir;
+
+/// Version of the on-disk format, bumped on incompatible changes.
+///
+/// 2: records may carry an expiry time, and so do hints.
+/// 3: records carry a version, and so do hints.
+/// 4: a record may hold a batch of records.
+/// 5: records carry the time they were written at, and so do hints.
+/// 6: a record may be a merge operand, and hints tell which are.
+pub(crate) const FORMAT_VERSION: u32 = 6;
+
+/// The list of live log files.
+///
+/// Log files only become part of the store once a manifest naming them has
+/// been published, and stop being part of it as soon as one that leaves them
+/// out has been. Publishing is an atomic rename, so compaction either fully
+/// happened or not at all, whenever the process dies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Manifest {
+    format: u32,
+    /// Generations of the live log files, oldest first.
+    pub(crate) gens: Vec<u64>,
+    /// A version no record written before the manifest was published is
+    /// above, even one compaction has since dropped.
+    #[serde(default)]
+    pub(crate) last_version: u64,
+}
+
+impl Manifest {
+    pub(crate) fn new(gens: Vec<u64>) -> Manifest {
+        Manifest { format: FORMAT_VERSION, gens, last_version: 0 }
+    }
+
+    /// Reads the manifest of `dir`, if it has one.
+    pub(crate) fn load(dir: &LogDir) -> Result<Option<Manifest>> {
+        let file = match File::open(dir.manifest_path()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let manifest: Manifest = serde_json::from_reader(BufReader::new(file)).map_err(io::Error::from)?;
+        // older formats are upgraded by the store, newer ones are unknown
+        if manifest.format > FORMAT_VERSION {
+            return Err(KvsError::IncompatibleFormat(manifest.format));
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Whether the log files were written in the current format, rather
+    /// than one the store still has to upgrade from.
+    pub(crate) fn is_current(&self) -> bool {
+        self.format == FORMAT_VERSION
+    }
+
+    /// Atomically replaces the manifest of `dir` with this one.
+    pub(crate) fn publish(&self, dir: &LogDir) -> Result<()> {
+        let path = dir.manifest_path();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, self).map_err(io::Error::from)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        dir.sync()?;
+        Ok(())
+    }
+}
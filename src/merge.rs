@@ -0,0 +1,125 @@
+use std::collections::BTreeSet;
+
+use crate::kv::{KvsError, Result};
+
+/// Folds merge operands into the value of a key, see `KvStore::merge`.
+///
+/// Operands are written as log records of their own and only folded when
+/// the key is read, or when compaction rewrites them. Folding a run of
+/// operands in one go must give the same value as folding it a few at a
+/// time, since compaction may fold the older ones long before a read.
+pub trait MergeOperator: Send + Sync {
+    /// Folds `operands`, oldest first, into `existing`, the value `key` had
+    /// before them if any.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Result<Vec<u8>>;
+}
+
+/// Appends each operand to the value, with a separator in between.
+#[derive(Debug, Clone, Default)]
+pub struct Append {
+    separator: Vec<u8>,
+}
+
+impl Append {
+    pub fn new() -> Append {
+        Append::default()
+    }
+
+    pub fn with_separator<S: AsRef<[u8]>>(separator: S) -> Append {
+        Append { separator: separator.as_ref().to_vec() }
+    }
+}
+
+impl MergeOperator for Append {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Result<Vec<u8>> {
+        let mut value = existing.map_or_else(Vec::new, <[u8]>::to_vec);
+        for (i, operand) in operands.iter().enumerate() {
+            if existing.is_some() || i > 0 {
+                value.extend_from_slice(&self.separator);
+            }
+            value.extend_from_slice(operand);
+        }
+        Ok(value)
+    }
+}
+
+/// Keeps the largest of the value and the operands, all decimal integers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Max;
+
+impl MergeOperator for Max {
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Result<Vec<u8>> {
+        let mut max = match existing {
+            Some(existing) => Some(parse_integer(key, existing)?),
+            None => None,
+        };
+        for operand in operands {
+            let operand = parse_integer(key, operand)?;
+            max = Some(max.map_or(operand, |max: i64| max.max(operand)));
+        }
+        Ok(max.map(|max| max.to_string().into_bytes()).unwrap_or_default())
+    }
+}
+
+/// Keeps the union of the value and the operands, each a set of elements
+/// separated by a given byte. The elements are kept sorted.
+#[derive(Debug, Clone, Copy)]
+pub struct SetUnion {
+    separator: u8,
+}
+
+impl SetUnion {
+    pub fn new(separator: u8) -> SetUnion {
+        SetUnion { separator }
+    }
+}
+
+impl MergeOperator for SetUnion {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Result<Vec<u8>> {
+        let elements: BTreeSet<&[u8]> = existing
+            .into_iter()
+            .chain(operands.iter().copied())
+            .flat_map(|set| set.split(|byte| *byte == self.separator))
+            .filter(|element| !element.is_empty())
+            .collect();
+        let mut value = Vec::new();
+        for (i, element) in elements.into_iter().enumerate() {
+            if i > 0 {
+                value.push(self.separator);
+            }
+            value.extend_from_slice(element);
+        }
+        Ok(value)
+    }
+}
+
+/// Parses the value of `key` as a decimal integer.
+pub(crate) fn parse_integer(key: &[u8], value: &[u8]) -> Result<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| KvsError::NotAnInteger(String::from_utf8_lossy(key).into_owned()))
+}
+
+
+#[test]
+fn builtin_operators() -> Result<()> {
+    let append = Append::with_separator(",");
+    assert_eq!(append.merge(b"k", None, &[b"a", b"b"])?, b"a,b");
+    assert_eq!(append.merge(b"k", Some(b"a"), &[b"b"])?, b"a,b");
+    assert_eq!(Append::new().merge(b"k", Some(b"a"), &[b"b", b"c"])?, b"abc");
+
+    assert_eq!(Max.merge(b"k", Some(b"5"), &[b"3", b"-7", b"12"])?, b"12");
+    assert_eq!(Max.merge(b"k", None, &[b"-3"])?, b"-3");
+    match Max.merge(b"k", Some(b"5"), &[b"five"]) {
+        Err(KvsError::NotAnInteger(key)) => assert_eq!(key, "k"),
+        other => panic!("expected NotAnInteger, got {:?}", other),
+    }
+
+    let union = SetUnion::new(b',');
+    assert_eq!(union.merge(b"k", Some(b"b,a"), &[b"c,a", b"", b"b"])?, b"a,b,c");
+    // folding in steps gives the same value
+    let first = union.merge(b"k", None, &[b"x,y"])?;
+    assert_eq!(union.merge(b"k", Some(&first), &[b"z"])?, union.merge(b"k", None, &[b"x,y", b"z"])?);
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::durability::SyncPolicy;
+use crate::history::Retention;
+use crate::kv::{KvStore, Result};
+use crate::merge::MergeOperator;
+use crate::pool::DEFAULT_MAX_OPEN_FILES;
+use crate::segment::DEFAULT_MAX_SEGMENT_SIZE;
+
+/// Options for opening a `KvStore`, in the spirit of `std::fs::OpenOptions`.
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use rust_kv::{KvStoreOptions, SyncPolicy};
+/// let store = KvStoreOptions::new()
+///     .sync_policy(SyncPolicy::Always)
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .open(Path::new("/var/lib/kvs"))?;
+/// # Ok::<(), rust_kv::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) data_dir: PathBuf,
+    pub(crate) log_prefix: String,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_ratio: f64,
+    pub(crate) max_segment_size: u64,
+    pub(crate) max_open_files: usize,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) read_only: bool,
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) verify_on_open: bool,
+    pub(crate) retention: Retention,
+    pub(crate) merge_operator: Option<SharedMergeOperator>,
+    pub(crate) progress: Option<ProgressCallback>,
+}
+
+/// How far `open` got with rebuilding the index, see
+/// `KvStoreOptions::on_progress`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenProgress {
+    /// Generation of the log file just read.
+    pub segment: u64,
+    /// Whether it was read through its hints rather than replayed.
+    pub from_hints: bool,
+    /// Log files read so far, out of `segments_total`.
+    pub segments_done: usize,
+    pub segments_total: usize,
+    /// Bytes of log files read so far, out of `bytes_total`.
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+/// A progress callback, shared by clones of the options.
+#[derive(Clone)]
+pub(crate) struct ProgressCallback(Arc<dyn Fn(&OpenProgress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub(crate) fn report(&self, progress: &OpenProgress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// A merge operator, shared by clones of the options and by the store.
+#[derive(Clone)]
+pub(crate) struct SharedMergeOperator(pub(crate) Arc<dyn MergeOperator>);
+
+impl fmt::Debug for SharedMergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            data_dir: PathBuf::from(".kvs"),
+            log_prefix: "log-".to_owned(),
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: 0.0,
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            sync: SyncPolicy::default(),
+            read_only: false,
+            create_if_missing: true,
+            error_if_exists: false,
+            verify_on_open: false,
+            retention: Retention::default(),
+            merge_operator: None,
+            progress: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Directory holding the log files, relative to the path given to
+    /// `open`. Defaults to `.kvs`, an empty path uses the directory itself.
+    pub fn data_dir<P: AsRef<Path>>(&mut self, data_dir: P) -> &mut KvStoreOptions {
+        self.data_dir = data_dir.as_ref().to_owned();
+        self
+    }
+
+    /// Prefix of the log file names, which end with their generation.
+    /// Defaults to `log-`.
+    pub fn log_prefix(&mut self, log_prefix: &str) -> &mut KvStoreOptions {
+        self.log_prefix = log_prefix.to_owned();
+        self
+    }
+
+    /// Compacts once more than `bytes` of the log are stale, rewriting the
+    /// dirtiest log files until at most half of that is left. Defaults to
+    /// 1 MiB.
+    pub fn compaction_threshold(&mut self, bytes: u64) -> &mut KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Only compacts once at least this fraction (`0.0` to `1.0`) of the log
+    /// is stale, on top of `compaction_threshold`. Defaults to `0.0`.
+    pub fn compaction_ratio(&mut self, ratio: f64) -> &mut KvStoreOptions {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Seals the active log file and starts a new one once it would grow
+    /// beyond `bytes`. A single record larger than that gets a log file of
+    /// its own. Defaults to 64 MiB.
+    pub fn max_segment_size(&mut self, bytes: u64) -> &mut KvStoreOptions {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// How many log files are kept open for reading. Defaults to 64.
+    pub fn max_open_files(&mut self, max_open_files: usize) -> &mut KvStoreOptions {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// When writes are flushed to disk. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(&mut self, sync: SyncPolicy) -> &mut KvStoreOptions {
+        self.sync = sync;
+        self
+    }
+
+    /// Opens an existing store without ever modifying it, every write
+    /// fails with `KvsError::ReadOnly`.
+    pub fn read_only(&mut self, read_only: bool) -> &mut KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Creates the store if it does not exist yet. Defaults to `true`.
+    pub fn create_if_missing(&mut self, create_if_missing: bool) -> &mut KvStoreOptions {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Fails with `KvsError::StoreExists` if the store already exists.
+    pub fn error_if_exists(&mut self, error_if_exists: bool) -> &mut KvStoreOptions {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Replays every log file when opening, checking each record against
+    /// its checksum, instead of reading sealed log files through their
+    /// hints. Defaults to `false`.
+    ///
+    /// Hints are only used if the last 4 KiB of their log file did not
+    /// change, so without this option corruption further from the end of a
+    /// sealed log file is not reported by `open` but by the first `get` that
+    /// runs into it.
+    pub fn verify_on_open(&mut self, verify_on_open: bool) -> &mut KvStoreOptions {
+        self.verify_on_open = verify_on_open;
+        self
+    }
+
+    /// Keeps up to `versions` older values of each key, to be read with
+    /// `KvStore::get_at` and `KvStore::history`, rather than leaving them to
+    /// compaction. Defaults to 0.
+    pub fn keep_versions(&mut self, versions: usize) -> &mut KvStoreOptions {
+        self.retention.versions = versions;
+        self
+    }
+
+    /// Keeps every older value of a key that was still its value less than
+    /// `window` ago, on top of those `keep_versions` keeps.
+    pub fn keep_history_for(&mut self, window: Duration) -> &mut KvStoreOptions {
+        self.retention.window = Some(window);
+        self
+    }
+
+    /// Folds the operands written by `KvStore::merge` into values. A store
+    /// holding operands has to be opened with the operator that wrote them:
+    /// without one, reading a merged value fails with
+    /// `KvsError::NoMergeOperator`.
+    pub fn merge_operator<M: MergeOperator + 'static>(&mut self, operator: M) -> &mut KvStoreOptions {
+        self.merge_operator = Some(SharedMergeOperator(Arc::new(operator)));
+        self
+    }
+
+    /// Calls `callback` after each log file read while `open` rebuilds the
+    /// index. Progress is logged at info level either way.
+    pub fn on_progress<F>(&mut self, callback: F) -> &mut KvStoreOptions
+    where
+        F: Fn(&OpenProgress) + Send + Sync + 'static,
+    {
+        self.progress = Some(ProgressCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Opens the store in `path` with these options.
+    pub fn open(&self, path: &Path) -> Result<KvStore> {
+        KvStore::open_with_options(path, self)
+    }
+}
+
+
+#[cfg(test)]
+use crate::engine::KvsEngine;
+#[cfg(test)]
+use crate::kv::KvsError;
+#[cfg(test)]
+use tempfile::TempDir;
+
+#[test]
+fn custom_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .data_dir("data")
+        .log_prefix("segment.")
+        .open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    assert!(temp_dir.path().join("data").join("segment.1").exists());
+    assert!(!temp_dir.path().join(".kvs").exists());
+    drop(store);
+
+    let store = KvStoreOptions::new().data_dir("").log_prefix("x").open(&temp_dir.path().join("data"))?;
+    assert_eq!(store.get("key1")?, None);
+    drop(store);
+    let store = KvStoreOptions::new()
+        .data_dir("data")
+        .log_prefix("segment.")
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn create_and_exist_checks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    match KvStoreOptions::new().create_if_missing(false).open(temp_dir.path()) {
+        Err(KvsError::StoreNotFound(_)) => {}
+        _ => panic!("expected StoreNotFound"),
+    }
+
+    let store = KvStoreOptions::new().error_if_exists(true).open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    drop(store);
+
+    match KvStoreOptions::new().error_if_exists(true).open(temp_dir.path()) {
+        Err(KvsError::StoreExists(_)) => {}
+        _ => panic!("expected StoreExists"),
+    }
+    let store = KvStoreOptions::new().create_if_missing(false).open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    match KvStoreOptions::new().read_only(true).open(temp_dir.path()) {
+        Err(KvsError::StoreNotFound(_)) => {}
+        _ => panic!("expected StoreNotFound"),
+    }
+
+    KvStore::open(temp_dir.path())?.set("key1", "value1")?;
+    let store = KvStoreOptions::new().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    match store.set("key1", "value2") {
+        Err(KvsError::ReadOnly) => {}
+        _ => panic!("expected ReadOnly"),
+    }
+    match store.remove("key1") {
+        Err(KvsError::ReadOnly) => {}
+        _ => panic!("expected ReadOnly"),
+    }
+    Ok(())
+}
+
+#[test]
+fn segment_size_and_compaction_trigger() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(256)
+        .compaction_threshold(4096)
+        .compaction_ratio(0.5)
+        .clone();
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let logs = || std::fs::read_dir(temp_dir.path().join(".kvs")).unwrap().count();
+    assert!(logs() > 1, "segments should roll over");
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // overwrite until more than half of the log is stale
+    let mut compacted = false;
+    let mut current_logs = logs();
+    for round in 0..20 {
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        store.wait_for_compaction();
+        if logs() < current_logs {
+            compacted = true;
+            break;
+        }
+        current_logs = logs();
+    }
+    assert!(compacted, "compaction should have been triggered");
+
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        assert!(store.get(format!("key{}", i))?.is_some());
+    }
+    Ok(())
+}
@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use log::{debug, warn};
+
+use crate::layout::LogDir;
+
+/// Default number of log files kept open for reading.
+pub(crate) const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// A bounded, least-recently-used cache of read-only log file handles.
+///
+/// Handles are shared between all readers, which is fine because reads go
+/// through `read_exact_at` and never touch the file cursor. A handle evicted
+/// while a read is in flight stays open until that read drops its `Arc`, and
+/// a retired log file is only removed once the last such read is done.
+pub(crate) struct FilePool {
+    dir: Arc<LogDir>,
+    capacity: usize,
+    inner: Mutex<PoolInner>,
+}
+
+#[derive(Default)]
+struct PoolInner {
+    // gen -> (handle, last time it was handed out)
+    files: HashMap<u64, (Arc<LogFile>, u64)>,
+    // every handle handed out, including those evicted but still in use
+    handles: HashMap<u64, Weak<LogFile>>,
+    tick: u64,
+    // gen -> number of snapshots reading it
+    pins: HashMap<u64, usize>,
+    // pinned files retired meanwhile, retired for good once unpinned
+    retired: HashSet<u64>,
+}
+
+/// Log files kept on disk for a snapshot until this is dropped, see
+/// `FilePool::pin`.
+pub(crate) struct Pinned {
+    pool: Arc<FilePool>,
+    gens: Vec<u64>,
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        self.pool.unpin(&self.gens);
+    }
+}
+
+/// An open log file, removed from disk when dropped after being retired.
+pub(crate) struct LogFile {
+    file: File,
+    path: PathBuf,
+    retired: AtomicBool,
+}
+
+impl Deref for LogFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        if self.retired.load(Ordering::Acquire) {
+            remove_log(&self.path);
+        }
+    }
+}
+
+fn remove_log(path: &Path) {
+    debug!("Removing retired log file {}", path.display());
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to remove retired log file {}: {}", path.display(), e);
+    }
+}
+
+impl FilePool {
+    pub(crate) fn new(dir: Arc<LogDir>, capacity: usize) -> FilePool {
+        FilePool {
+            dir,
+            capacity: capacity.max(1),
+            inner: Mutex::new(PoolInner::default()),
+        }
+    }
+
+    /// Returns a handle to log file `gen`, opening it if needed.
+    pub(crate) fn get(&self, gen: u64) -> io::Result<Arc<LogFile>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some((file, last_used)) = inner.files.get_mut(&gen) {
+            *last_used = tick;
+            return Ok(file.clone());
+        }
+
+        if inner.files.len() >= self.capacity {
+            let lru = inner
+                .files
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(gen, _)| *gen)
+                .expect("pool should not be empty");
+            debug!("Closing log file {} to stay under {} open files", lru, self.capacity);
+            inner.files.remove(&lru);
+        }
+
+        // a reader may still hold a handle that was evicted earlier
+        let file = match inner.handles.get(&gen).and_then(Weak::upgrade) {
+            Some(file) => file,
+            None => {
+                let path = self.dir.log_path(gen);
+                let file = File::open(&path)?;
+                Arc::new(LogFile { file, path, retired: AtomicBool::new(false) })
+            }
+        };
+        inner.handles.insert(gen, Arc::downgrade(&file));
+        inner.files.insert(gen, (file.clone(), tick));
+        Ok(file)
+    }
+
+    /// Removes log file `gen` from disk once no read is using it anymore.
+    ///
+    /// The index must no longer point into the file, so that no new reads
+    /// can start on it.
+    pub(crate) fn retire(&self, gen: u64) {
+        let file = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.pins.contains_key(&gen) {
+                debug!("Keeping retired log file {} for a snapshot", gen);
+                inner.retired.insert(gen);
+                return;
+            }
+            inner.files.remove(&gen);
+            inner.handles.remove(&gen).and_then(|file| file.upgrade())
+        };
+        match file {
+            // removed when the last `Arc` is dropped, possibly right here
+            Some(file) => file.retired.store(true, Ordering::Release),
+            None => remove_log(&self.dir.log_path(gen)),
+        }
+    }
+
+    /// Keeps log files `gens` from being removed when they are retired,
+    /// until the returned `Pinned` is dropped.
+    pub(crate) fn pin(self: &Arc<Self>, gens: Vec<u64>) -> Pinned {
+        let mut inner = self.inner.lock().unwrap();
+        for &gen in &gens {
+            *inner.pins.entry(gen).or_insert(0) += 1;
+        }
+        Pinned { pool: self.clone(), gens }
+    }
+
+    fn unpin(&self, gens: &[u64]) {
+        let mut released = Vec::new();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for gen in gens {
+                let pins = inner.pins.get_mut(gen).expect("pinned log file");
+                *pins -= 1;
+                if *pins == 0 {
+                    inner.pins.remove(gen);
+                    if inner.retired.remove(gen) {
+                        released.push(*gen);
+                    }
+                }
+            }
+        }
+        for gen in released {
+            self.retire(gen);
+        }
+    }
+
+    #[cfg(test)]
+    fn open_files(&self) -> usize {
+        self.inner.lock().unwrap().files.len()
+    }
+}
+
+/// Reads exactly `buf.len()` bytes at `offset` without moving the cursor.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+/// Reads exactly `buf.len()` bytes at `offset`.
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+
+#[test]
+fn pool_evicts_least_recently_used() -> io::Result<()> {
+    let temp_dir = tempfile::TempDir::new().expect("unable to create temporary working directory");
+    let dir = Arc::new(LogDir::new(temp_dir.path().to_owned(), "log-".to_owned()));
+    for gen in 1..=3 {
+        std::fs::write(dir.log_path(gen), format!("log {}", gen))?;
+    }
+    let pool = FilePool::new(dir, 2);
+
+    let first = pool.get(1)?;
+    pool.get(2)?;
+    pool.get(1)?;
+    pool.get(3)?;
+    assert_eq!(pool.open_files(), 2);
+    assert!(Arc::ptr_eq(&first, &pool.get(1)?), "recently used file was evicted");
+
+    let mut buf = [0; 5];
+    read_exact_at(&first, &mut buf, 0)?;
+    assert_eq!(&buf, b"log 1");
+    Ok(())
+}
+
+#[test]
+fn retired_file_outlives_reads() -> io::Result<()> {
+    let temp_dir = tempfile::TempDir::new().expect("unable to create temporary working directory");
+    let dir = Arc::new(LogDir::new(temp_dir.path().to_owned(), "log-".to_owned()));
+    for gen in 1..=3 {
+        std::fs::write(dir.log_path(gen), format!("log {}", gen))?;
+    }
+    let pool = FilePool::new(dir.clone(), 1);
+
+    // a read in flight on a handle the pool has already evicted
+    let in_flight = pool.get(1)?;
+    pool.get(2)?;
+    pool.retire(1);
+    pool.retire(2);
+    pool.retire(3);
+    assert!(dir.log_path(1).exists());
+    assert!(!dir.log_path(2).exists());
+    assert!(!dir.log_path(3).exists());
+
+    let mut buf = [0; 5];
+    read_exact_at(&in_flight, &mut buf, 0)?;
+    assert_eq!(&buf, b"log 1");
+    drop(in_flight);
+    assert!(!dir.log_path(1).exists());
+    Ok(())
+}
+
+#[test]
+fn pinned_file_outlives_retirement() -> io::Result<()> {
+    let temp_dir = tempfile::TempDir::new().expect("unable to create temporary working directory");
+    let dir = Arc::new(LogDir::new(temp_dir.path().to_owned(), "log-".to_owned()));
+    for gen in 1..=2 {
+        std::fs::write(dir.log_path(gen), format!("log {}", gen))?;
+    }
+    let pool = Arc::new(FilePool::new(dir.clone(), 1));
+
+    let first = pool.pin(vec![1, 2]);
+    let second = pool.pin(vec![1]);
+    pool.retire(1);
+    pool.retire(2);
+    assert!(dir.log_path(1).exists() && dir.log_path(2).exists());
+
+    // still readable while pinned
+    let mut buf = [0; 5];
+    read_exact_at(&*pool.get(1)?, &mut buf, 0)?;
+    assert_eq!(&buf, b"log 1");
+
+    drop(first);
+    assert!(dir.log_path(1).exists());
+    assert!(!dir.log_path(2).exists());
+    drop(second);
+    assert!(!dir.log_path(1).exists());
+    Ok(())
+}
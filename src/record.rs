@@ -0,0 +1,196 @@
+use std::io::{self, Read};
+
+/// Size of the header in front of every record.
+pub(crate) const HEADER_LEN: u64 = 8;
+
+/// Wraps `payload` into a record: `[length: u32][crc32: u32][payload]`.
+///
+/// The checksum covers the length field as well as the payload.
+pub(crate) fn encode(payload: &[u8]) -> Vec<u8> {
+    let length = (payload.len() as u32).to_le_bytes();
+    let mut buf = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    buf.extend_from_slice(&length);
+    buf.extend_from_slice(&checksum(length, payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Checks a whole record read back from disk and returns its payload.
+///
+/// Returns `None` if the record is truncated or does not match its checksum.
+pub(crate) fn decode(record: &[u8]) -> Option<&[u8]> {
+    if (record.len() as u64) < HEADER_LEN {
+        return None;
+    }
+    let (header, payload) = record.split_at(HEADER_LEN as usize);
+    let (length, crc) = parse_header(header);
+    if payload.len() as u64 != length || checksum(header_length(header), payload) != crc {
+        return None;
+    }
+    Some(payload)
+}
+
+fn checksum(length: [u8; 4], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&length);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn header_length(header: &[u8]) -> [u8; 4] {
+    [header[0], header[1], header[2], header[3]]
+}
+
+fn parse_header(header: &[u8]) -> (u64, u32) {
+    let length = u32::from_le_bytes(header_length(header));
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    (u64::from(length), crc)
+}
+
+/// What `RecordScanner` found at its current position.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Scanned {
+    /// A valid record of `length` bytes (header included) at `offset`.
+    Record { offset: u64, length: u64, payload: Vec<u8> },
+    /// The end of the file, right after a valid record.
+    End,
+    /// The last record at `offset` was only partially written.
+    Torn { offset: u64 },
+    /// The record at `offset` is damaged although more data follows it.
+    Corrupt { offset: u64 },
+}
+
+/// Reads the records of a log file front to back.
+///
+/// A write interrupted by a crash can only damage the last record of a file,
+/// so a bad record that runs up to the end of the file is reported as `Torn`
+/// while one followed by more data is reported as `Corrupt`.
+pub(crate) struct RecordScanner<R> {
+    reader: R,
+    offset: u64,
+    file_len: u64,
+}
+
+impl<R: Read> RecordScanner<R> {
+    pub(crate) fn new(reader: R, file_len: u64) -> RecordScanner<R> {
+        RecordScanner { reader, offset: 0, file_len }
+    }
+
+    pub(crate) fn next(&mut self) -> io::Result<Scanned> {
+        let offset = self.offset;
+        let remaining = self.file_len - offset;
+        if remaining == 0 {
+            return Ok(Scanned::End);
+        }
+        if remaining < HEADER_LEN {
+            return Ok(Scanned::Torn { offset });
+        }
+
+        let mut header = [0; HEADER_LEN as usize];
+        self.reader.read_exact(&mut header)?;
+        let (length, crc) = parse_header(&header);
+        if HEADER_LEN + length > remaining {
+            // a damaged length can also run past the end of the file, but
+            // then the records it hides still follow, up to the end
+            let mut rest = Vec::new();
+            (&mut self.reader).take(remaining - HEADER_LEN).read_to_end(&mut rest)?;
+            return Ok(if ends_in_records(&rest) {
+                Scanned::Corrupt { offset }
+            } else {
+                Scanned::Torn { offset }
+            });
+        }
+
+        let mut payload = vec![0; length as usize];
+        self.reader.read_exact(&mut payload)?;
+        let length = HEADER_LEN + length;
+        if checksum(header_length(&header), &payload) != crc {
+            return Ok(if length == remaining {
+                Scanned::Torn { offset }
+            } else {
+                Scanned::Corrupt { offset }
+            });
+        }
+
+        self.offset += length;
+        Ok(Scanned::Record { offset, length, payload })
+    }
+}
+
+/// Whether a run of valid records starting anywhere in `bytes` lasts up to
+/// its end.
+fn ends_in_records(bytes: &[u8]) -> bool {
+    (0..bytes.len()).any(|start| {
+        let mut rest = &bytes[start..];
+        while let Some(length) = record_len(rest) {
+            rest = &rest[length..];
+            if rest.is_empty() {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+/// The length of the valid record at the start of `bytes`, if there is one.
+fn record_len(bytes: &[u8]) -> Option<usize> {
+    if (bytes.len() as u64) < HEADER_LEN {
+        return None;
+    }
+    let length = (HEADER_LEN + parse_header(bytes).0) as usize;
+    if length > bytes.len() {
+        return None;
+    }
+    decode(&bytes[..length]).map(|_| length)
+}
+
+
+#[test]
+fn scan_valid_records() -> io::Result<()> {
+    let mut log = encode(b"first");
+    log.extend(encode(b""));
+    log.extend(encode(b"third"));
+    assert_eq!(decode(&log[..13]), Some(&b"first"[..]));
+
+    let mut scanner = RecordScanner::new(&log[..], log.len() as u64);
+    assert_eq!(scanner.next()?, Scanned::Record { offset: 0, length: 13, payload: b"first".to_vec() });
+    assert_eq!(scanner.next()?, Scanned::Record { offset: 13, length: 8, payload: vec![] });
+    assert_eq!(scanner.next()?, Scanned::Record { offset: 21, length: 13, payload: b"third".to_vec() });
+    assert_eq!(scanner.next()?, Scanned::End);
+    Ok(())
+}
+
+#[test]
+fn scan_tells_torn_tail_from_corruption() -> io::Result<()> {
+    let mut log = encode(b"first");
+    log.extend(encode(b"second"));
+
+    // a partially written last record is torn
+    for cut in &[log.len() - 1, 16, 14] {
+        let mut scanner = RecordScanner::new(&log[..*cut], *cut as u64);
+        scanner.next()?;
+        assert_eq!(scanner.next()?, Scanned::Torn { offset: 13 });
+    }
+
+    // a flipped bit in the last record is torn as well
+    let mut damaged = log.clone();
+    *damaged.last_mut().unwrap() ^= 1;
+    let mut scanner = RecordScanner::new(&damaged[..], damaged.len() as u64);
+    scanner.next()?;
+    assert_eq!(scanner.next()?, Scanned::Torn { offset: 13 });
+
+    // but a flipped bit followed by more records is corruption
+    let mut damaged = log.clone();
+    damaged[10] ^= 1;
+    assert_eq!(decode(&damaged[..13]), None);
+    let mut scanner = RecordScanner::new(&damaged[..], damaged.len() as u64);
+    assert_eq!(scanner.next()?, Scanned::Corrupt { offset: 0 });
+
+    // as is a length running past the end of the file with records after it
+    let mut damaged = log.clone();
+    damaged.extend(encode(b"third"));
+    damaged[1] ^= 1;
+    let mut scanner = RecordScanner::new(&damaged[..], damaged.len() as u64);
+    assert_eq!(scanner.next()?, Scanned::Corrupt { offset: 0 });
+    Ok(())
+}
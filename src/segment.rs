@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::time::SystemTime;
+
+/// Default size at which the writer rolls over to a new log file.
+pub(crate) const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A log file of a store, as reported by `KvStore::segments`.
+///
+/// Only the newest segment is ever appended to. The others are sealed: they
+/// are on disk and never change again until compaction removes them, which
+/// makes them safe to copy for backups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Generation of the log file, which ends its file name.
+    pub id: u64,
+    /// Bytes of all records in the file.
+    pub size: u64,
+    /// Bytes of the records that are still current.
+    pub live_bytes: u64,
+    /// When the log file was created.
+    pub created: SystemTime,
+    /// Whether the log file is sealed.
+    pub sealed: bool,
+}
+
+/// Byte counts of one log file, kept up to date by the writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SegmentStats {
+    /// Bytes of all records in the file.
+    pub(crate) total: u64,
+    /// Bytes of records that were overwritten or removed, and of tombstones.
+    pub(crate) dead: u64,
+    pub(crate) created: SystemTime,
+}
+
+impl SegmentStats {
+    pub(crate) fn new(created: SystemTime) -> SegmentStats {
+        SegmentStats { total: 0, dead: 0, created }
+    }
+
+    /// Stats of an existing log file, whose records are yet to be counted.
+    pub(crate) fn of_file(metadata: &Metadata) -> SegmentStats {
+        // not every file system records creation times
+        let created = metadata.created().or_else(|_| metadata.modified());
+        SegmentStats::new(created.unwrap_or_else(|_| SystemTime::now()))
+    }
+
+    pub(crate) fn info(&self, id: u64, sealed: bool) -> SegmentInfo {
+        SegmentInfo {
+            id,
+            size: self.total,
+            live_bytes: self.total.saturating_sub(self.dead),
+            created: self.created,
+            sealed,
+        }
+    }
+
+    fn dead_ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.dead as f64 / self.total as f64
+    }
+}
+
+/// Picks the log files worth compacting, the dirtiest first, until at most
+/// `target` dead bytes would be left in the others.
+///
+/// The picked generations are returned in the order of `gens`.
+pub(crate) fn pick_dirtiest(gens: &[u64], stats: &HashMap<u64, SegmentStats>, target: u64) -> Vec<u64> {
+    let mut candidates: Vec<(u64, SegmentStats)> = gens
+        .iter()
+        .filter_map(|gen| stats.get(gen).map(|stats| (*gen, *stats)))
+        .filter(|(_, stats)| stats.dead > 0)
+        .collect();
+    candidates.sort_by(|(_, a), (_, b)| {
+        b.dead_ratio().partial_cmp(&a.dead_ratio()).unwrap().then(b.dead.cmp(&a.dead))
+    });
+
+    let mut remaining: u64 = candidates.iter().map(|(_, stats)| stats.dead).sum();
+    let mut picked = Vec::new();
+    for (gen, stats) in candidates {
+        if remaining <= target {
+            break;
+        }
+        remaining -= stats.dead;
+        picked.push(gen);
+    }
+    gens.iter().copied().filter(|gen| picked.contains(gen)).collect()
+}
+
+
+#[test]
+fn pick_dirtiest_segments_first() {
+    let stats = |total, dead| SegmentStats { total, dead, created: SystemTime::now() };
+    let stats: HashMap<u64, SegmentStats> =
+        vec![(1, stats(1000, 100)), (2, stats(1000, 900)), (3, stats(100, 0)), (4, stats(400, 200))]
+            .into_iter()
+            .collect();
+    let gens = [1, 2, 3, 4];
+
+    assert_eq!(pick_dirtiest(&gens, &stats, 1200), Vec::<u64>::new());
+    assert_eq!(pick_dirtiest(&gens, &stats, 300), vec![2]);
+    assert_eq!(pick_dirtiest(&gens, &stats, 250), vec![2, 4]);
+    assert_eq!(pick_dirtiest(&gens, &stats, 0), vec![1, 2, 4]);
+}
@@ -0,0 +1,199 @@
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::thread;
+
+use log::{debug, error};
+
+use crate::common::{
+    read_frame, write_frame, BatchResponse, ConditionalResponse, DbSizeResponse, ExistsResponse, GetResponse, GetVersionedResponse,
+    IncrResponse, KeysResponse, MergeResponse, RemoveResponse, Request, ScanResponse, SetResponse, TransactionResponse,
+};
+use crate::engine::{KvsEngine, Result};
+use crate::txn::Transaction;
+
+/// Answer to requests that cannot run inside a transaction.
+const NOT_IN_TRANSACTION: &str = "Not allowed inside a transaction";
+
+/// A server that answers `Request`s with any `KvsEngine`.
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    pub fn new(engine: E) -> Self {
+        KvsServer { engine }
+    }
+
+    /// Listens on `addr` and serves every client on its own thread.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(engine, stream) {
+                            error!("Error on serving client: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    debug!("new client! {}", peer_addr);
+
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    macro_rules! send_resp {
+        ($resp:expr) => {{
+            let resp = $resp;
+            write_frame(&mut writer, &resp)?;
+            debug!("Response sent to {}: {:?}", peer_addr, resp);
+        }};
+    }
+
+    // the transaction started by `Begin`, if any
+    let mut txn: Option<Transaction<E>> = None;
+
+    while let Some(req) = read_frame::<_, Request>(&mut reader)? {
+        debug!("Receive request from {}: {:?}", peer_addr, req);
+        match req {
+            Request::Get { key } => {
+                let value = match txn {
+                    Some(ref mut txn) => txn.get(key),
+                    None => engine.get_bytes(key),
+                };
+                send_resp!(match value {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(e) => GetResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Set { key, value } => {
+                let result = match txn {
+                    Some(ref mut txn) => {
+                        txn.set(key, value);
+                        Ok(())
+                    }
+                    None => engine.set_bytes(key, value),
+                };
+                send_resp!(match result {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Remove { key } => {
+                let result = match txn {
+                    Some(ref mut txn) => txn.remove(key),
+                    None => engine.remove_bytes(key),
+                };
+                send_resp!(match result {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Begin if txn.is_some() => {
+                send_resp!(TransactionResponse::Err("A transaction is already running".to_owned()))
+            }
+            Request::Begin => send_resp!(match engine.begin() {
+                Ok(started) => {
+                    txn = Some(started);
+                    TransactionResponse::Ok(())
+                }
+                Err(e) => TransactionResponse::Err(format!("{}", e)),
+            }),
+            Request::Commit => send_resp!(match txn.take().map(Transaction::commit) {
+                Some(Ok(_)) => TransactionResponse::Ok(()),
+                Some(Err(e)) => TransactionResponse::Err(format!("{}", e)),
+                None => TransactionResponse::Err("No transaction is running".to_owned()),
+            }),
+            Request::Rollback => send_resp!(match txn.take() {
+                Some(_) => TransactionResponse::Ok(()),
+                None => TransactionResponse::Err("No transaction is running".to_owned()),
+            }),
+            // only `Get`, `Set` and `Remove` go through a transaction, the
+            // other requests would bypass it
+            Request::Scan { .. } | Request::ScanPrefix { .. } if txn.is_some() => {
+                send_resp!(ScanResponse::Err(NOT_IN_TRANSACTION.to_owned()))
+            }
+            Request::Keys { .. } if txn.is_some() => send_resp!(KeysResponse::Err(NOT_IN_TRANSACTION.to_owned())),
+            Request::Exists { .. } if txn.is_some() => send_resp!(ExistsResponse::Err(NOT_IN_TRANSACTION.to_owned())),
+            Request::DbSize if txn.is_some() => send_resp!(DbSizeResponse::Err(NOT_IN_TRANSACTION.to_owned())),
+            Request::GetVersioned { .. } if txn.is_some() => {
+                send_resp!(GetVersionedResponse::Err(NOT_IN_TRANSACTION.to_owned()))
+            }
+            Request::CompareAndSwap { .. } | Request::SetIfAbsent { .. } | Request::SetIfVersion { .. } if txn.is_some() => {
+                send_resp!(ConditionalResponse::Err(NOT_IN_TRANSACTION.to_owned()))
+            }
+            Request::Batch { .. } if txn.is_some() => send_resp!(BatchResponse::Err(NOT_IN_TRANSACTION.to_owned())),
+            Request::IncrBy { .. } if txn.is_some() => send_resp!(IncrResponse::Err(NOT_IN_TRANSACTION.to_owned())),
+            Request::Merge { .. } if txn.is_some() => send_resp!(MergeResponse::Err(NOT_IN_TRANSACTION.to_owned())),
+            Request::Scan { start, end, after, limit } => {
+                let start = start.as_deref().map_or(Bound::Unbounded, Bound::Included);
+                let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                send_resp!(match engine.scan_bytes(start, end, after.as_deref(), limit as usize) {
+                    Ok(page) => ScanResponse::Ok(page),
+                    Err(e) => ScanResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::ScanPrefix { prefix, after, limit } => {
+                send_resp!(match engine.scan_prefix_bytes(&prefix, after.as_deref(), limit as usize) {
+                    Ok(page) => ScanResponse::Ok(page),
+                    Err(e) => ScanResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Keys { pattern } => send_resp!(match engine.keys_matching(&pattern) {
+                Ok(keys) => KeysResponse::Ok(keys),
+                Err(e) => KeysResponse::Err(format!("{}", e)),
+            }),
+            Request::Exists { key } => send_resp!(match engine.contains_key_bytes(key) {
+                Ok(exists) => ExistsResponse::Ok(exists),
+                Err(e) => ExistsResponse::Err(format!("{}", e)),
+            }),
+            Request::DbSize => send_resp!(match engine.key_count() {
+                Ok(count) => DbSizeResponse::Ok(count),
+                Err(e) => DbSizeResponse::Err(format!("{}", e)),
+            }),
+            Request::GetVersioned { key } => send_resp!(match engine.get_versioned(key) {
+                Ok(entry) => GetVersionedResponse::Ok(entry),
+                Err(e) => GetVersionedResponse::Err(format!("{}", e)),
+            }),
+            Request::CompareAndSwap { key, expected, new } => {
+                send_resp!(match engine.compare_and_swap(key, expected, new) {
+                    Ok(swapped) => ConditionalResponse::Ok(swapped),
+                    Err(e) => ConditionalResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::SetIfAbsent { key, value } => send_resp!(match engine.set_if_absent(key, value) {
+                Ok(set) => ConditionalResponse::Ok(set),
+                Err(e) => ConditionalResponse::Err(format!("{}", e)),
+            }),
+            Request::SetIfVersion { key, value, version } => {
+                send_resp!(match engine.set_if_version(key, value, version) {
+                    Ok(set) => ConditionalResponse::Ok(set),
+                    Err(e) => ConditionalResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Batch { batch } => send_resp!(match engine.write_batch(&batch) {
+                Ok(_) => BatchResponse::Ok(()),
+                Err(e) => BatchResponse::Err(format!("{}", e)),
+            }),
+            Request::IncrBy { key, delta } => send_resp!(match engine.incr_by(key, delta) {
+                Ok(value) => IncrResponse::Ok(value),
+                Err(e) => IncrResponse::Err(format!("{}", e)),
+            }),
+            Request::Merge { key, operand } => send_resp!(match engine.merge_bytes(key, operand) {
+                Ok(_) => MergeResponse::Ok(()),
+                Err(e) => MergeResponse::Err(format!("{}", e)),
+            }),
+        };
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+
+use crate::batch::WriteBatch;
+use crate::engine::KvsEngine;
+use crate::kv::{KvsError, Result, Snapshot};
+
+/// A read-then-write transaction across keys, started by
+/// `KvsEngine::begin` or run by `KvStore::transaction`.
+///
+/// Writes are buffered and committed as one `WriteBatch`. Reads see the
+/// transaction's own writes, and otherwise the store as it was when the
+/// transaction began: a read fails with `KvsError::Conflict` right away if
+/// the key was written since. They record the version of what they read: the
+/// commit fails with `KvsError::Conflict` if any key read was changed since,
+/// or any key written was changed after the transaction began.
+///
+/// A transaction started by `KvStore::begin_snapshot` reads from a
+/// `Snapshot` instead, so it still sees the values replaced since.
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    // highest version written before the transaction began
+    start: u64,
+    // the store as of `start`, if the engine can provide it
+    snapshot: Option<Snapshot>,
+    // version of every key read, `None` if it did not exist
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+    // buffered writes, `None` removing the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E, start: u64) -> Transaction<E> {
+        Transaction {
+            engine,
+            start,
+            snapshot: None,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Starts a transaction that reads from `snapshot`, which holds exactly
+    /// the writes up to version `start`.
+    pub(crate) fn with_snapshot(engine: E, start: u64, snapshot: Snapshot) -> Transaction<E> {
+        Transaction { snapshot: Some(snapshot), ..Transaction::new(engine, start) }
+    }
+
+    /// Gets the value of a given key, as written by this transaction or as
+    /// in the store when it began.
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let entry = match self.snapshot {
+            Some(ref snapshot) => snapshot.get_versioned(key)?,
+            None => match self.engine.get_versioned(key)? {
+                // the value it had when the transaction began is gone
+                Some((_, version)) if version > self.start => {
+                    return Err(KvsError::Conflict(String::from_utf8_lossy(key).into_owned()));
+                }
+                entry => entry,
+            },
+        };
+        // the first read is the one the commit checks against
+        self.reads.entry(key.to_vec()).or_insert_with(|| entry.as_ref().map(|(_, version)| *version));
+        Ok(entry.map(|(value, _)| value))
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.writes.insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+    }
+
+    /// Removes a given key when the transaction commits.
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not found.
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        let key = key.as_ref();
+        if self.get(key)?.is_none() {
+            return Err(KvsError::NonExistentKey(String::from_utf8_lossy(key).into_owned()));
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Atomically applies the writes of the transaction.
+    ///
+    /// Returns `KvsError::Conflict` if another write got in the way, in
+    /// which case nothing is written.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in &self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        self.engine.commit_transaction(&self.reads, self.start, &batch)
+    }
+}
@@ -0,0 +1,57 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use log::info;
+use serde::{Deserialize, Serialize};
+use crate::kv::Result;
+use crate::record;
+
+/// A log record as the first versions of the store wrote it: the bincode of
+/// `(command, key, value)` with no header, right after the previous one.
+///
+/// Its encoding is still what a framed record holds for a set or a remove.
+#[derive(Serialize, Deserialize)]
+struct UnframedCommand {
+    typ: u32,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+/// Whether the log file at `path` was written before records had a header.
+///
+/// Unframed files start with a command, set (0) or remove (1), where framed
+/// ones start with the length of their first record, which is never below
+/// the 20 bytes an empty set takes.
+pub(crate) fn is_unframed(path: &Path) -> Result<bool> {
+    let mut start = [0; 4];
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() < start.len() as u64 {
+        return Ok(false);
+    }
+    file.read_exact(&mut start)?;
+    Ok(u32::from_le_bytes(start) <= 1)
+}
+
+/// Rewrites the log file at `path` with a header in front of every record.
+///
+/// The framed file replaces the old one with a rename, so a crash leaves
+/// either of them in place.
+pub(crate) fn frame_log(path: &Path) -> Result<()> {
+    info!("Upgrading '{}' to framed records", path.display());
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    // like the old store, reading stops at the first record that does not
+    // decode, which a crash may have left half written
+    while let Ok(cmd) = bincode::deserialize_from::<_, UnframedCommand>(&mut reader) {
+        if cmd.typ > 1 {
+            break;
+        }
+        writer.write_all(&record::encode(&bincode::serialize(&cmd)?))?;
+    }
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}